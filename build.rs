@@ -0,0 +1,29 @@
+use std::path::Path;
+use std::process::Command;
+
+// exposes the commit as GIT_SHA, unless it's already provided by the build environment
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    // a commit moves the branch HEAD points to, which is either a loose ref or packed, a
+    // missing path would count as changed on every build
+    let mut refs = vec![".git/packed-refs".to_owned()];
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        if let Some(branch) = head.trim().strip_prefix("ref: ") {
+            refs.push(format!(".git/{}", branch));
+        }
+    }
+    for path in refs.iter().filter(|path| Path::new(path).exists()) {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+    if std::env::var_os("GIT_SHA").is_some() {
+        return;
+    }
+    let output = Command::new("git").args(["rev-parse", "HEAD"]).output();
+    if let Ok(output) = output {
+        if output.status.success() {
+            let sha = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=GIT_SHA={}", sha.trim());
+        }
+    }
+}
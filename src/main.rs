@@ -1,347 +1,8513 @@
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use opentelemetry_prometheus::PrometheusExporter;
 use std::error::Error;
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
 use tracing_bunyan_formatter as bunyan;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{reload, EnvFilter};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let tracer = observability::init_tracer()?;
+    let service_name = config::service_name();
+    // a broken ratio samples every trace, it's only logged once the subscriber is installed
+    let sampler_ratio = config::sampler_ratio();
+    let tracer = observability::init_tracer(&service_name, *sampler_ratio.as_ref().unwrap_or(&1.))?;
+    let span_counter = observability::SpanCounter::default();
+    let (env_filter, log_filter) = reload::Layer::new(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    );
     tracing_subscriber::Registry::default()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(env_filter)
         .with(bunyan::JsonStorageLayer)
         .with(bunyan::BunyanFormattingLayer::new(
-            env!("CARGO_PKG_NAME").into(),
+            service_name,
             std::io::stdout,
         ))
         .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .with(span_counter.clone())
         .init();
+    if let Err(err) = sampler_ratio {
+        tracing::warn!(%err, "sampling every trace");
+    }
     let metrics_exporter = observability::init_metrics_exporter()?;
+    let addr = config::bind_addr()?;
+    let tls = config::tls_config()?;
+    let api_config = config::ApiConfig {
+        log_filter: Some(observability::LogFilter::new(log_filter)),
+        ..config::api_config()?
+    };
+    let (signalled, mut shutdown_started) = oneshot::channel();
+    let shutdown = shutdown_signal().map(|()| {
+        let _ = signalled.send(Instant::now());
+    });
+
+    match std::env::var("STORAGE") {
+        #[cfg(feature = "sqlite")]
+        Ok(url) if url.starts_with("sqlite:") => {
+            let repo = models::SqliteRepo::connect(&url).await?;
+            serve_metrics_format(repo, metrics_exporter, api_config, addr, tls, shutdown).await?;
+        }
+        Ok(url) if url == "dashmap" => {
+            let repo = models::DashMapRepo::new();
+            serve_metrics_format(repo, metrics_exporter, api_config, addr, tls, shutdown).await?;
+        }
+        Ok(url) if url != "memory" => return Err(format!("unsupported STORAGE: {}", url).into()),
+        _ => {
+            let repo = models::InMemoryRepo::new(models::init_state());
+            serve_metrics_format(repo, metrics_exporter, api_config, addr, tls, shutdown).await?;
+        }
+    }
+    let started = shutdown_started
+        .try_recv()
+        .unwrap_or_else(|_| Instant::now());
 
-    let state = models::init_state();
-    let api = filters::users(state, metrics_exporter);
-    warp::serve(api).run(([127, 0, 0, 1], 3030)).await;
+    // flush the spans still buffered in the batch exporter, the metrics are pulled by
+    // Prometheus, so there is no meter provider to flush
+    let flushed = flush_with_timeout(
+        FLUSH_TIMEOUT,
+        opentelemetry::global::shutdown_tracer_provider,
+    )
+    .await;
+    if !flushed {
+        tracing::warn!(timeout = ?FLUSH_TIMEOUT, "gave up flushing the remaining spans");
+    }
+    ShutdownSummary {
+        spans: span_counter.count(),
+        flushed,
+        duration: started.elapsed(),
+    }
+    .log();
 
     Ok(())
 }
 
-mod filters {
-    use super::handlers;
-    use super::models::{State, User};
-    use super::observability::{record_metrics, MetricsExporter};
-    use std::convert::Infallible;
-    use warp::Filter;
+/// The last line logged before exiting, telling a clean exit apart from a crash.
+struct ShutdownSummary {
+    spans: u64,
+    flushed: bool,
+    duration: Duration,
+}
 
-    pub fn users(
-        state: State,
-        metrics_exporter: impl MetricsExporter,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        list_users(state.clone())
-            .or(create_user(state))
-            .or(metrics(metrics_exporter))
-            .with(warp::trace::request())
-            .with(warp::log::custom(record_metrics))
+impl ShutdownSummary {
+    // the log still goes out after the tracer provider is shut down, as the subscriber stays
+    // installed until the process exits
+    fn log(&self) {
+        tracing::info!(
+            spans.ended = self.spans,
+            spans.flushed = self.flushed,
+            duration_ms = self.duration.as_millis() as u64,
+            "shutdown complete"
+        );
+        // nothing is written to stdout after this, so make sure the line isn't left in a buffer
+        let _ = std::io::stdout().flush();
     }
+}
 
-    pub fn list_users(
-        state: State,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("users")
-            .and(warp::get())
-            .and(with_state(state))
-            .and_then(handlers::list_users)
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs a blocking flush on its own thread, returning `false` if it didn't finish in time.
+async fn flush_with_timeout(timeout: Duration, flush: impl FnOnce() + Send + 'static) -> bool {
+    let (done, finished) = oneshot::channel();
+    // a detached thread, unlike a blocking task, doesn't keep the runtime from shutting down
+    std::thread::spawn(move || {
+        flush();
+        let _ = done.send(());
+    });
+    tokio::time::timeout(timeout, finished).await.is_ok()
+}
+
+async fn serve(
+    repo: impl models::Repository,
+    metrics_exporter: impl observability::MetricsExporter,
+    api_config: config::ApiConfig,
+    addr: SocketAddr,
+    tls: Option<config::TlsConfig>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), warp::Error> {
+    // persistent storage can hold users from earlier runs
+    match repo.list().await {
+        Ok(users) => {
+            let active = users.iter().filter(|user| user.deleted_at.is_none());
+            observability::record_users_loaded(active.count())
+        }
+        Err(err) => tracing::warn!(?err, "failed to count stored users"),
+    }
+    let shutdown = shutdown.shared();
+    let admin_port = api_config.admin_port;
+    let api = filters::users(repo, metrics_exporter.clone(), &api_config);
+    let (addr, server) = bind(api, addr, tls.as_ref(), shutdown.clone())?;
+    tracing::info!(%addr, tls = tls.is_some(), "listening");
+    match admin_port {
+        Some(port) => {
+            let admin = filters::admin(metrics_exporter, api_config.metrics_token);
+            let (admin_addr, admin_server) = warp::serve(admin)
+                .try_bind_with_graceful_shutdown(SocketAddr::new(addr.ip(), port), shutdown)?;
+            tracing::info!(%admin_addr, "admin listening");
+            futures::join!(server, admin_server);
+        }
+        None => server.await,
     }
+    tracing::info!("server stopped");
+    Ok(())
+}
 
-    pub fn create_user(
-        state: State,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("users")
-            .and(warp::post())
-            .and(json_body())
-            .and(with_state(state))
-            .and_then(handlers::create_user)
+/// Binds the API, serving HTTPS if a certificate and key are configured.
+fn bind<F>(
+    api: F,
+    addr: SocketAddr,
+    tls: Option<&config::TlsConfig>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(SocketAddr, BoxFuture<'static, ()>), warp::Error>
+where
+    F: warp::Filter + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    match tls {
+        // unlike the plain server, the TLS one panics if it can't bind or load the files
+        #[cfg(feature = "tls")]
+        Some(tls) => {
+            let (addr, server) = warp::serve(api)
+                .tls()
+                .cert_path(&tls.cert_path)
+                .key_path(&tls.key_path)
+                .bind_with_graceful_shutdown(addr, shutdown);
+            Ok((addr, server.boxed()))
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(_) => unreachable!("TLS is rejected by the config without the tls feature"),
+        None => {
+            let (addr, server) =
+                warp::serve(api).try_bind_with_graceful_shutdown(addr, shutdown)?;
+            Ok((addr, server.boxed()))
+        }
     }
+}
 
-    pub fn metrics(
-        exporter: impl MetricsExporter,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("metrics")
-            .and(warp::get())
-            .and(with_exporter(exporter))
-            .and_then(handlers::metrics)
+async fn serve_metrics_format(
+    repo: impl models::Repository,
+    metrics_exporter: PrometheusExporter,
+    api_config: config::ApiConfig,
+    addr: SocketAddr,
+    tls: Option<config::TlsConfig>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Box<dyn Error>> {
+    observability::observe_users_current(repo.clone());
+    observability::observe_users_by_gender(repo.clone());
+    match config::metrics_format()? {
+        config::MetricsFormat::Prometheus => {
+            serve(repo, metrics_exporter, api_config, addr, tls, shutdown).await?
+        }
+        config::MetricsFormat::Json => {
+            let exporter = observability::JsonExporter::new(metrics_exporter.registry().clone());
+            serve(repo, exporter, api_config, addr, tls, shutdown).await?
+        }
     }
+    Ok(())
+}
 
-    fn with_state(state: State) -> impl Filter<Extract = (State,), Error = Infallible> + Clone {
-        warp::any().map(move || state.clone())
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
     }
+    tracing::info!("shutdown signal received");
+}
 
-    fn with_exporter(
-        exporter: impl MetricsExporter,
-    ) -> impl Filter<Extract = (impl MetricsExporter,), Error = Infallible> + Clone {
-        warp::any().map(move || exporter.clone())
+mod config {
+    use super::observability::LogFilter;
+    use std::error::Error;
+    use std::fmt;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use warp::http::Uri;
+
+    const DEFAULT_BIND_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const DEFAULT_PORT: u16 = 3030;
+
+    #[derive(Debug)]
+    pub struct ConfigError {
+        var: &'static str,
+        value: String,
     }
 
-    fn json_body() -> impl Filter<Extract = (User,), Error = warp::Rejection> + Clone {
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    impl fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "invalid value for {}: {:?}", self.var, self.value)
+        }
     }
-}
 
-mod handlers {
-    use super::models::{State, User};
-    use super::observability::MetricsExporter;
-    use std::convert::Infallible;
-    use tracing::instrument;
-    use warp::http::StatusCode;
+    impl Error for ConfigError {}
 
-    #[instrument(skip(state))]
-    pub async fn list_users(state: State) -> Result<impl warp::Reply, Infallible> {
-        let users = state.lock().await.clone();
-        Ok(warp::reply::json(&users))
+    fn var(name: &'static str) -> Option<String> {
+        std::env::var(name).ok()
     }
 
-    #[instrument(skip(state))]
-    pub async fn create_user(user: User, state: State) -> Result<impl warp::Reply, Infallible> {
-        let mut users = state.lock().await;
-        users.push(user);
-        Ok(StatusCode::CREATED)
+    /// Settings of the API routes.
+    #[derive(Clone, Default)]
+    pub struct ApiConfig {
+        /// Bearer token required by the mutating routes, which are unprotected without one.
+        pub api_token: Option<String>,
+        /// User creations allowed per client IP and minute, unlimited if unset.
+        pub create_rate_limit: Option<u32>,
+        /// Origins allowed to make cross-origin requests, CORS is disabled if unset.
+        pub cors_origins: Option<CorsOrigins>,
+        /// Time after which the user routes give up on a request.
+        pub request_timeout: Option<Duration>,
+        /// Largest request body accepted, 16KiB if unset.
+        pub max_body_bytes: Option<u64>,
+        /// Handle to change the log level at runtime, the route is disabled without one.
+        pub log_filter: Option<LogFilter>,
+        /// Port of a separate server for the operational routes, which also takes the metrics
+        /// off the API port.
+        pub admin_port: Option<u16>,
+        /// Whether the user routes reject requests without an `X-Tenant-Id` header.
+        pub require_tenant: bool,
+        /// Requests handled at the same time, further ones are shed, unlimited if unset.
+        pub max_concurrent_requests: Option<usize>,
+        /// Bearer token required by `/metrics`, which is public without one.
+        pub metrics_token: Option<String>,
     }
 
-    pub async fn metrics(exporter: impl MetricsExporter) -> Result<impl warp::Reply, Infallible> {
-        let buf = exporter.export();
-        Ok(buf)
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum CorsOrigins {
+        Any,
+        List(Vec<String>),
     }
-}
 
-mod models {
-    use serde::{Deserialize, Serialize};
-    use std::sync::Arc;
-    use tokio::sync::Mutex;
+    pub fn api_config() -> Result<ApiConfig, ConfigError> {
+        let api_token = var("API_TOKEN").filter(|token| !token.is_empty());
+        let metrics_token = parse_metrics_token(
+            parse_flag("PROTECT_METRICS", var("PROTECT_METRICS").as_deref())?,
+            var("METRICS_TOKEN").as_deref(),
+            api_token.as_deref(),
+        )?;
+        Ok(ApiConfig {
+            api_token,
+            create_rate_limit: parse_rate_limit(var("CREATE_RATE_LIMIT").as_deref())?,
+            cors_origins: parse_cors_origins(var("CORS_ORIGINS").as_deref())?,
+            request_timeout: parse_request_timeout(var("REQUEST_TIMEOUT_MS").as_deref())?,
+            max_body_bytes: parse_max_body_bytes(var("MAX_BODY_BYTES").as_deref())?,
+            log_filter: None,
+            admin_port: parse_admin_port(var("ADMIN_PORT").as_deref())?,
+            require_tenant: parse_flag("REQUIRE_TENANT", var("REQUIRE_TENANT").as_deref())?,
+            max_concurrent_requests: parse_max_concurrent_requests(
+                var("MAX_CONCURRENT_REQUESTS").as_deref(),
+            )?,
+            metrics_token,
+        })
+    }
 
-    pub type State = Arc<Mutex<Vec<User>>>;
+    /// Protected metrics take `METRICS_TOKEN`, falling back to the API token.
+    pub fn parse_metrics_token(
+        protect: bool,
+        metrics_token: Option<&str>,
+        api_token: Option<&str>,
+    ) -> Result<Option<String>, ConfigError> {
+        if !protect {
+            return Ok(None);
+        }
+        match metrics_token
+            .filter(|token| !token.is_empty())
+            .or(api_token)
+        {
+            Some(token) => Ok(Some(token.into())),
+            // protecting the metrics without any token would leave them public
+            None => Err(ConfigError {
+                var: "METRICS_TOKEN",
+                value: metrics_token.unwrap_or_default().into(),
+            }),
+        }
+    }
 
-    pub fn init_state() -> State {
-        Arc::new(Mutex::new(Vec::new()))
+    pub fn parse_flag(var: &'static str, value: Option<&str>) -> Result<bool, ConfigError> {
+        match value.map(str::trim) {
+            None | Some("") | Some("false") | Some("0") => Ok(false),
+            Some("true") | Some("1") => Ok(true),
+            Some(value) => Err(ConfigError {
+                var,
+                value: value.into(),
+            }),
+        }
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    #[serde(rename_all = "camelCase")]
-    pub enum Gender {
-        Female,
-        Male,
-        Unspecified,
+    pub fn parse_admin_port(value: Option<&str>) -> Result<Option<u16>, ConfigError> {
+        value
+            .map(|port| {
+                port.parse().map_err(|_| ConfigError {
+                    var: "ADMIN_PORT",
+                    value: port.into(),
+                })
+            })
+            .transpose()
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    #[serde(rename_all = "camelCase")]
-    pub struct User {
-        pub id: u64,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        pub first_name: Option<String>,
-        pub last_name: String,
-        pub gender: Gender,
+    pub fn parse_request_timeout(value: Option<&str>) -> Result<Option<Duration>, ConfigError> {
+        match value {
+            None => Ok(None),
+            Some(value) => match value.parse() {
+                Ok(millis) if millis > 0 => Ok(Some(Duration::from_millis(millis))),
+                _ => Err(ConfigError {
+                    var: "REQUEST_TIMEOUT_MS",
+                    value: value.into(),
+                }),
+            },
+        }
     }
-}
 
-mod observability {
-    use lazy_static::lazy_static;
-    use opentelemetry::metrics::MetricsError;
-    use opentelemetry::metrics::{Counter, ValueRecorder};
-    use opentelemetry::sdk;
-    use opentelemetry::trace::TraceError;
-    use opentelemetry::KeyValue;
-    use opentelemetry::{global, Unit};
-    use opentelemetry_prometheus::PrometheusExporter;
-    use prometheus::Encoder;
-    use std::convert::{TryFrom, TryInto};
-    use warp::log::Info;
+    pub fn parse_max_body_bytes(value: Option<&str>) -> Result<Option<u64>, ConfigError> {
+        match value {
+            None => Ok(None),
+            Some(value) => match value.parse() {
+                Ok(bytes) if bytes > 0 => Ok(Some(bytes)),
+                _ => Err(ConfigError {
+                    var: "MAX_BODY_BYTES",
+                    value: value.into(),
+                }),
+            },
+        }
+    }
 
-    struct Meters {
-        pub incoming_requests: Counter<u64>,
-        pub duration: ValueRecorder<u64>,
-        pub status_codes: Counter<u64>,
+    pub fn parse_max_concurrent_requests(
+        value: Option<&str>,
+    ) -> Result<Option<usize>, ConfigError> {
+        match value {
+            None => Ok(None),
+            Some(value) => match value.parse() {
+                Ok(limit) if limit > 0 => Ok(Some(limit)),
+                _ => Err(ConfigError {
+                    var: "MAX_CONCURRENT_REQUESTS",
+                    value: value.into(),
+                }),
+            },
+        }
     }
 
-    lazy_static! {
-        static ref METERS: Meters = {
-            let meter = global::meter("web-service");
-            let incoming_requests = meter.u64_counter("incoming_requests").init();
-            let duration = meter
-                .u64_value_recorder("http.server.duration")
-                .with_unit(Unit::new("milliseconds"))
-                .init();
-            let status_codes = meter.u64_counter("status_codes").init();
-            Meters {
-                incoming_requests,
-                duration,
-                status_codes,
+    pub fn parse_cors_origins(value: Option<&str>) -> Result<Option<CorsOrigins>, ConfigError> {
+        let value = match value.map(str::trim) {
+            None | Some("") => return Ok(None),
+            Some("*") => return Ok(Some(CorsOrigins::Any)),
+            Some(value) => value,
+        };
+        let origins: Vec<String> = value
+            .split(',')
+            .map(|origin| origin.trim().to_owned())
+            .collect();
+        // warp panics on origins which are not of the form scheme://host[:port]
+        let valid = |origin: &String| match origin.split_once("://") {
+            Some((scheme, host)) => {
+                !scheme.is_empty()
+                    && !host.is_empty()
+                    && !host.contains('/')
+                    && origin.parse::<Uri>().is_ok()
             }
+            None => false,
         };
+        if origins.iter().all(valid) {
+            Ok(Some(CorsOrigins::List(origins)))
+        } else {
+            Err(ConfigError {
+                var: "CORS_ORIGINS",
+                value: value.into(),
+            })
+        }
     }
 
-    pub fn init_metrics_exporter() -> Result<PrometheusExporter, MetricsError> {
-        opentelemetry_prometheus::exporter()
-            .with_default_histogram_boundaries(vec![
-                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.,
-            ])
-            .try_init()
+    pub fn parse_rate_limit(value: Option<&str>) -> Result<Option<u32>, ConfigError> {
+        match value {
+            None => Ok(None),
+            Some(value) => match value.parse() {
+                Ok(limit) if limit > 0 => Ok(Some(limit)),
+                _ => Err(ConfigError {
+                    var: "CREATE_RATE_LIMIT",
+                    value: value.into(),
+                }),
+            },
+        }
     }
 
-    pub fn init_tracer() -> Result<sdk::trace::Tracer, TraceError> {
-        opentelemetry_jaeger::new_pipeline()
-            .with_service_name(env!("CARGO_PKG_NAME"))
-            .install_batch(opentelemetry::runtime::Tokio)
+    pub fn bind_addr() -> Result<SocketAddr, ConfigError> {
+        parse_bind_addr(var("BIND_ADDR").as_deref(), var("PORT").as_deref())
     }
 
-    pub struct ServiceMetrics {
-        pub duration_ms: u64,
-        pub status_family: &'static str,
-        pub method: &'static str,
-        pub path: &'static str,
+    pub fn parse_bind_addr(
+        addr: Option<&str>,
+        port: Option<&str>,
+    ) -> Result<SocketAddr, ConfigError> {
+        let ip = match addr {
+            Some(addr) => addr.parse().map_err(|_| ConfigError {
+                var: "BIND_ADDR",
+                value: addr.into(),
+            })?,
+            None => DEFAULT_BIND_ADDR,
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| ConfigError {
+                var: "PORT",
+                value: port.into(),
+            })?,
+            None => DEFAULT_PORT,
+        };
+        Ok(SocketAddr::new(ip, port))
     }
 
-    impl ServiceMetrics {
-        fn duration_labels(&self) -> Vec<KeyValue> {
-            [
-                KeyValue::new("http.status_code", self.status_family),
-                KeyValue::new("http.method", self.method),
-                KeyValue::new("http.target", self.path),
-            ]
-            .into()
+    /// Paths of the PEM files with the certificate chain and private key to serve HTTPS with.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct TlsConfig {
+        pub cert_path: PathBuf,
+        pub key_path: PathBuf,
+    }
+
+    /// Only one of `TLS_CERT` and `TLS_KEY` is set.
+    #[derive(Debug, PartialEq)]
+    pub struct IncompleteTlsConfig {
+        set: &'static str,
+        missing: &'static str,
+    }
+
+    impl fmt::Display for IncompleteTlsConfig {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{} is set without {}, HTTPS needs both",
+                self.set, self.missing
+            )
         }
+    }
 
-        fn status_code_labels(&self) -> Vec<KeyValue> {
-            [
-                KeyValue::new("http.status_code", self.status_family),
-                KeyValue::new("http.method", self.method),
-            ]
-            .into()
+    impl Error for IncompleteTlsConfig {}
+
+    pub fn tls_config() -> Result<Option<TlsConfig>, Box<dyn Error>> {
+        let tls = parse_tls_config(var("TLS_CERT").as_deref(), var("TLS_KEY").as_deref())?;
+        if let Some(tls) = &tls {
+            if !cfg!(feature = "tls") {
+                return Err("HTTPS needs a build with the tls feature".into());
+            }
+            check_pem("TLS_CERT", &tls.cert_path)?;
+            check_pem("TLS_KEY", &tls.key_path)?;
         }
+        Ok(tls)
+    }
 
-        fn record(&self) {
-            METERS.incoming_requests.add(1, &[]);
-            METERS
-                .duration
-                .record(self.duration_ms, &self.duration_labels());
-            METERS.status_codes.add(1, &self.status_code_labels());
+    /// warp panics on an unreadable or malformed file when binding, so it's checked upfront.
+    pub fn check_pem(var: &'static str, path: &std::path::Path) -> Result<(), ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(pem) if pem.contains("-----BEGIN ") => Ok(()),
+            _ => Err(ConfigError {
+                var,
+                value: path.display().to_string(),
+            }),
         }
     }
 
-    impl TryFrom<&Info<'_>> for ServiceMetrics {
-        type Error = &'static str;
+    /// Plain HTTP is served if neither path is set.
+    pub fn parse_tls_config(
+        cert_path: Option<&str>,
+        key_path: Option<&str>,
+    ) -> Result<Option<TlsConfig>, IncompleteTlsConfig> {
+        match (cert_path, key_path) {
+            (None, None) => Ok(None),
+            (Some(cert_path), Some(key_path)) => Ok(Some(TlsConfig {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            })),
+            (Some(_), None) => Err(IncompleteTlsConfig {
+                set: "TLS_CERT",
+                missing: "TLS_KEY",
+            }),
+            (None, Some(_)) => Err(IncompleteTlsConfig {
+                set: "TLS_KEY",
+                missing: "TLS_CERT",
+            }),
+        }
+    }
 
-        fn try_from(info: &Info) -> Result<Self, Self::Error> {
-            let duration_ms = info.elapsed().as_millis() as u64;
-            let status_family = match info.status().as_u16() {
-                500..=599 => Ok("500"),
-                400..=499 => Ok("400"),
-                300..=399 => Ok("300"),
-                200..=299 => Ok("200"),
-                100..=199 => Ok("100"),
-                _ => Err("unknown status code"),
-            }?;
-            let method = match info.method().as_str() {
-                "GET" => Ok("GET"),
-                "POST" => Ok("POST"),
-                _ => Err("unknown http method"),
-            }?;
-            let path = match info.path() {
-                "/users" => "/users",
-                _ => "invalid",
-            };
-            let metrics = Self {
-                duration_ms,
-                status_family,
-                method,
-                path,
-            };
+    #[derive(Debug, PartialEq)]
+    pub enum MetricsFormat {
+        Prometheus,
+        Json,
+    }
 
-            Ok(metrics)
-        }
+    pub fn metrics_format() -> Result<MetricsFormat, ConfigError> {
+        parse_metrics_format(var("METRICS_FORMAT").as_deref())
     }
 
-    pub trait MetricsExporter: Clone + Send {
-        fn export(&self) -> Vec<u8>;
+    pub fn service_name() -> String {
+        parse_service_name(var("SERVICE_NAME").as_deref())
     }
 
-    impl MetricsExporter for PrometheusExporter {
-        fn export(&self) -> Vec<u8> {
-            let encoder = prometheus::TextEncoder::new();
-            let metric_families = self.registry().gather();
-            let mut buf = Vec::new();
-            encoder.encode(&metric_families, &mut buf).unwrap();
-            buf
+    /// Parses the name reported in traces and logs, which defaults to the package name.
+    pub fn parse_service_name(value: Option<&str>) -> String {
+        value
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(env!("CARGO_PKG_NAME"))
+            .to_owned()
+    }
+
+    pub fn metrics_namespace() -> String {
+        parse_metrics_namespace(var("METRICS_NAMESPACE").as_deref())
+    }
+
+    /// Parses the prefix of the metric names, an empty value leaves the names unprefixed.
+    pub fn parse_metrics_namespace(value: Option<&str>) -> String {
+        value.unwrap_or("web_service").trim().to_owned()
+    }
+
+    pub fn parse_metrics_format(value: Option<&str>) -> Result<MetricsFormat, ConfigError> {
+        match value {
+            None | Some("prometheus") => Ok(MetricsFormat::Prometheus),
+            Some("json") => Ok(MetricsFormat::Json),
+            Some(value) => Err(ConfigError {
+                var: "METRICS_FORMAT",
+                value: value.into(),
+            }),
         }
     }
 
-    pub fn record_metrics(info: Info) {
-        if info.path() == "/metrics" {
-            return;
+    const DEFAULT_HISTOGRAM_BOUNDARIES: &[f64] =
+        &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+
+    pub fn histogram_boundaries() -> Vec<f64> {
+        parse_histogram_boundaries(var("HISTOGRAM_BOUNDARIES").as_deref())
+    }
+
+    /// Parses comma-separated, ascending bucket boundaries. Unlike the other settings a broken
+    /// value is not fatal, the defaults are used instead.
+    pub fn parse_histogram_boundaries(value: Option<&str>) -> Vec<f64> {
+        let value = match value {
+            Some(value) => value,
+            None => return DEFAULT_HISTOGRAM_BOUNDARIES.into(),
+        };
+        let boundaries: Result<Vec<f64>, _> =
+            value.split(',').map(|bound| bound.trim().parse()).collect();
+        match boundaries {
+            Ok(boundaries)
+                if boundaries.iter().all(|bound| bound.is_finite())
+                    && boundaries.windows(2).all(|pair| pair[0] < pair[1]) =>
+            {
+                boundaries
+            }
+            _ => {
+                tracing::warn!(%value, "invalid HISTOGRAM_BOUNDARIES, using the defaults");
+                DEFAULT_HISTOGRAM_BOUNDARIES.into()
+            }
         }
+    }
 
-        let result: Result<ServiceMetrics, _> = (&info).try_into();
-        if let Ok(metrics) = result {
-            metrics.record();
+    pub fn sampler_ratio() -> Result<f64, ConfigError> {
+        parse_sampler_ratio(var("OTEL_TRACES_SAMPLER_ARG").as_deref())
+    }
+
+    /// Parses the fraction of traces to sample, every trace is sampled if it's unset.
+    pub fn parse_sampler_ratio(value: Option<&str>) -> Result<f64, ConfigError> {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(1.),
         };
+        match value.trim().parse::<f64>() {
+            Ok(ratio) if (0. ..=1.).contains(&ratio) => Ok(ratio),
+            _ => Err(ConfigError {
+                var: "OTEL_TRACES_SAMPLER_ARG",
+                value: value.into(),
+            }),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::filters;
-    use super::models::init_state;
-    use super::observability::init_metrics_exporter;
-    use warp::http::StatusCode;
-    use warp::test::request;
+mod filters {
+    use super::config::{ApiConfig, CorsOrigins};
+    use super::handlers;
+    use super::handlers::{
+        Forbidden, IdempotencyCache, InvalidBody, InvalidQuery, MissingTenant, Overloaded,
+        Panicked, Timeout, TooManyRequests, Unauthorized, UnsupportedMethod, UserEvents,
+    };
+    use super::models::{FieldSelection, Pagination, Repository, Sorting, UserFilter};
+    use super::observability::{
+        current_trace_id, extract_context, log_access, record_metrics, record_panic,
+        record_request_size, record_response_size, record_trace_context, with_tenant, InFlight,
+        LogFilter, MetricsExporter,
+    };
+    use async_compression::tokio::bufread::{DeflateEncoder, GzipEncoder};
+    use futures::{FutureExt, TryStreamExt};
+    use serde::de::DeserializeOwned;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::panic::AssertUnwindSafe;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+    use tokio_util::io::{ReaderStream, StreamReader};
+    use tracing::field::{display, Empty};
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use uuid::Uuid;
+    use warp::filters::BoxedFilter;
+    use warp::http::header::{
+        ACCEPT_ENCODING, ACCEPT_LANGUAGE, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+    };
+    use warp::http::{HeaderMap, HeaderValue, Method, StatusCode};
+    use warp::hyper::body::{Bytes, HttpBody};
+    use warp::hyper::Body;
+    use warp::path::{FullPath, Peek};
+    use warp::reply::Response;
+    use warp::trace::{Info, Trace};
+    use warp::{Filter, Reply};
 
-    #[tokio::test]
-    async fn get_users() {
-        let api = filters::users(init_state(), init_metrics_exporter().unwrap());
+    pub fn users(
+        repo: impl Repository,
+        metrics_exporter: impl MetricsExporter,
+        config: &ApiConfig,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+        let token = config.api_token.clone();
+        let limiter = config.create_rate_limit.map(RateLimiter::per_minute);
+        let timeout = config.request_timeout;
+        let body_limit = config.max_body_bytes.unwrap_or(DEFAULT_BODY_LIMIT);
+        let events = UserEvents::default();
+        let routes = list_users(repo.clone(), timeout)
+            .or(count_users(repo.clone(), timeout))
+            .or(search_users(repo.clone(), timeout, body_limit))
+            .or(user_events(events.clone()))
+            .or(create_user(
+                repo.clone(),
+                token.clone(),
+                limiter,
+                IdempotencyCache::default(),
+                events.clone(),
+                timeout,
+                body_limit,
+            ))
+            .or(create_users_bulk(
+                repo.clone(),
+                token.clone(),
+                events,
+                timeout,
+                body_limit,
+            ))
+            .or(replace_users(
+                repo.clone(),
+                token.clone(),
+                timeout,
+                body_limit,
+            ))
+            .or(get_user(repo.clone(), timeout))
+            .or(replace_user(
+                repo.clone(),
+                token.clone(),
+                timeout,
+                body_limit,
+            ))
+            .or(patch_user(repo.clone(), token.clone(), timeout, body_limit))
+            .or(merge_patch_user(
+                repo.clone(),
+                token.clone(),
+                timeout,
+                body_limit,
+            ))
+            .or(json_patch_user(
+                repo.clone(),
+                token.clone(),
+                timeout,
+                body_limit,
+            ))
+            .or(delete_user(repo.clone(), token.clone(), timeout))
+            .or(warp::path!("users").and(unsupported_method("GET, HEAD, POST, PUT")))
+            .or(log_level(config.log_filter.clone(), token, body_limit))
+            .or(enabled(config.admin_port.is_none())
+                .and(metrics(metrics_exporter, config.metrics_token.clone())))
+            .or(health())
+            .or(ready(repo.clone()))
+            .or(version());
+        let semaphore = config
+            .max_concurrent_requests
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let routes = recover_localized(
+            concurrency_limit(semaphore)
+                .and(require_tenant(config.require_tenant))
+                .and(routes)
+                // the permit is released once the reply is ready
+                .map(|_permit, reply| reply),
+        );
+        // rejections of the CORS checks need to be recovered from as well
+        let routes = recover_localized(with_cors(routes, config.cors_origins.as_ref()));
+        // log::custom only fires on completion, so requests are counted as active up front
+        warp::path::full()
+            .and(content_length())
+            .map(|path: FullPath, content_length| {
+                // recorded before the routes run, so rejected bodies are measured too
+                record_request_size(path.as_str(), content_length);
+                (InFlight::start(path.as_str()), path)
+            })
+            .untuple_one()
+            .and(request_id())
+            .and(accept_encoding())
+            .and(routes)
+            .map(
+                |_in_flight: Option<InFlight>,
+                 path: FullPath,
+                 request_id,
+                 accept_encoding,
+                 reply| {
+                    let response = with_request_id(request_id, with_trace_id(reply));
+                    record_response_size(path.as_str(), &response);
+                    compress(accept_encoding, response)
+                },
+            )
+            .with(trace_request())
+            .with(warp::log::custom(record_metrics))
+            .with(warp::log::custom(log_access))
+    }
 
-        let response = request().method("GET").path("/users").reply(&api).await;
+    /// Operational routes for the admin port.
+    pub fn admin(
+        metrics_exporter: impl MetricsExporter,
+        metrics_token: Option<String>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+        metrics(metrics_exporter, metrics_token)
+            .or(health())
+            .or(version())
+            .recover(handlers::handle_rejection)
+            .with(trace_request())
+    }
 
-        assert_eq!(response.status(), StatusCode::OK);
+    pub fn list_users(
+        repo: impl Repository,
+        timeout: Option<Duration>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let list = warp::path!("users")
+            .and(warp::get().or(warp::head()).unify())
+            .and(query::<Pagination>())
+            .and(user_filter())
+            .and(query::<Sorting>())
+            .and(query::<FieldSelection>())
+            .and(warp::header::optional::<String>("accept"))
+            .and(warp::header::optional::<String>("if-none-match"))
+            .and(with_repo(repo))
+            .and_then(
+                move |pagination, filter, sorting, selection, accept, if_none_match, repo| {
+                    with_timeout(
+                        timeout,
+                        handlers::list_users(
+                            pagination,
+                            filter,
+                            sorting,
+                            selection,
+                            accept,
+                            if_none_match,
+                            repo,
+                        ),
+                    )
+                },
+            );
+        warp::method().and(list).map(without_body_on_head)
     }
 
-    #[tokio::test]
-    async fn create_user() {
-        let state = init_state();
-        let api = filters::users(state.clone(), init_metrics_exporter().unwrap());
+    /// Rejects the methods missing from `allow`, which are answered with a `405` listing the
+    /// allowed ones instead of the rejections of the routes sharing the path.
+    fn unsupported_method(
+        allow: &'static str,
+    ) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+        warp::method().and_then(move |method: Method| async move {
+            if allow.split(", ").any(|allowed| allowed == method.as_str()) {
+                Err(warp::reject::not_found())
+            } else {
+                Err(warp::reject::custom(UnsupportedMethod { allow }))
+            }
+        })
+    }
 
-        let response = request()
-            .method("POST")
-            .path("/users")
-            .body(
-                r#"{
-                    "firstName": "Jane",
-                    "lastName": "Doe",
-                    "gender": "female",
-                    "id": 123
-                }"#,
-            )
-            .reply(&api)
-            .await;
+    /// Answers HEAD requests with the headers of the GET response, but no body.
+    fn without_body_on_head(method: Method, reply: impl Reply) -> Response {
+        let response = reply.into_response();
+        if method != Method::HEAD {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        if let Some(length) = body.size_hint().exact() {
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(length));
+        }
+        Response::from_parts(parts, Body::empty())
+    }
 
-        assert_eq!(response.status(), StatusCode::CREATED);
-        let users = state.lock().await;
-        assert_eq!(users[0].id, 123);
+    pub fn count_users(
+        repo: impl Repository,
+        timeout: Option<Duration>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / "count")
+            .and(warp::get())
+            .and(user_filter())
+            .and(with_repo(repo))
+            .and_then(move |filter, repo| {
+                with_timeout(timeout, handlers::count_users(filter, repo))
+            })
     }
 
-    #[tokio::test]
-    async fn wrong_body() {
-        let state = init_state();
-        let api = filters::users(state.clone(), init_metrics_exporter().unwrap());
+    pub fn search_users(
+        repo: impl Repository,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / "search")
+            .and(warp::post())
+            .and(json_body(body_limit))
+            .and(with_repo(repo))
+            .and_then(move |search, repo| {
+                with_timeout(timeout, handlers::search_users(search, repo))
+            })
+    }
 
-        let response = request()
-            .method("POST")
+    pub fn create_user(
+        repo: impl Repository,
+        token: Option<String>,
+        limiter: Option<RateLimiter>,
+        idempotency: IdempotencyCache,
+        events: UserEvents,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users")
+            .and(warp::post())
+            .and(rate_limit(limiter))
+            .and(auth(token))
+            .and(json_body(body_limit))
+            .and(warp::header::optional::<String>("idempotency-key"))
+            .and(warp::any().map(move || idempotency.clone()))
+            .and(warp::any().map(move || events.clone()))
+            .and(with_repo(repo))
+            .and_then(move |new_user, key, idempotency, events, repo| {
+                with_timeout(
+                    timeout,
+                    handlers::create_user(new_user, key, idempotency, events, repo),
+                )
+            })
+    }
+
+    pub fn create_users_bulk(
+        repo: impl Repository,
+        token: Option<String>,
+        events: UserEvents,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / "batch")
+            .and(warp::post())
+            .and(auth(token))
+            .and(json_body(body_limit))
+            .and(warp::any().map(move || events.clone()))
+            .and(with_repo(repo))
+            .and_then(move |new_users, events, repo| {
+                with_timeout(
+                    timeout,
+                    handlers::create_users_bulk(new_users, events, repo),
+                )
+            })
+    }
+
+    // the stream stays open, so the request timeout doesn't apply
+    pub fn user_events(
+        events: UserEvents,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / "events")
+            .and(warp::get())
+            .and_then(move || {
+                let events = events.clone();
+                catch_panics(async move { Ok::<_, Infallible>(handlers::user_events(&events)) })
+            })
+    }
+
+    pub fn replace_users(
+        repo: impl Repository,
+        token: Option<String>,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users")
+            .and(warp::put())
+            .and(auth(token))
+            .and(json_body(body_limit))
+            .and(with_repo(repo))
+            .and_then(move |users, repo| {
+                with_timeout(timeout, handlers::replace_users(users, repo))
+            })
+    }
+
+    pub fn get_user(
+        repo: impl Repository,
+        timeout: Option<Duration>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / u64)
+            .and(warp::get())
+            .and(warp::header::optional::<String>("if-modified-since"))
+            .and(with_repo(repo))
+            .and_then(move |id, if_modified_since, repo| {
+                with_timeout(timeout, handlers::get_user(id, if_modified_since, repo))
+            })
+    }
+
+    pub fn replace_user(
+        repo: impl Repository,
+        token: Option<String>,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / u64)
+            .and(warp::put())
+            .and(auth(token))
+            .and(json_body(body_limit))
+            .and(warp::header::optional::<String>("if-match"))
+            .and(with_repo(repo))
+            .and_then(move |id, user, if_match, repo| {
+                with_timeout(timeout, handlers::replace_user(id, user, if_match, repo))
+            })
+    }
+
+    pub fn patch_user(
+        repo: impl Repository,
+        token: Option<String>,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / u64)
+            .and(warp::patch())
+            .and(auth(token))
+            .and(json_body(body_limit))
+            .and(warp::header::optional::<String>("if-match"))
+            .and(with_repo(repo))
+            .and_then(move |id, patch, if_match, repo| {
+                with_timeout(timeout, handlers::patch_user(id, patch, if_match, repo))
+            })
+    }
+
+    pub fn merge_patch_user(
+        repo: impl Repository,
+        token: Option<String>,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / u64)
+            .and(warp::patch())
+            .and(content_type(MERGE_PATCH))
+            .and(auth(token))
+            .and(raw_json_body(body_limit))
+            .and(warp::header::optional::<String>("if-match"))
+            .and(with_repo(repo))
+            .and_then(move |id, patch, if_match, repo| {
+                with_timeout(
+                    timeout,
+                    handlers::merge_patch_user(id, patch, if_match, repo),
+                )
+            })
+    }
+
+    pub fn json_patch_user(
+        repo: impl Repository,
+        token: Option<String>,
+        timeout: Option<Duration>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / u64)
+            .and(warp::patch())
+            .and(content_type(JSON_PATCH))
+            .and(auth(token))
+            .and(raw_json_body(body_limit))
+            .and(warp::header::optional::<String>("if-match"))
+            .and(with_repo(repo))
+            .and_then(move |id, patch, if_match, repo| {
+                with_timeout(
+                    timeout,
+                    handlers::json_patch_user(id, patch, if_match, repo),
+                )
+            })
+    }
+
+    pub fn delete_user(
+        repo: impl Repository,
+        token: Option<String>,
+        timeout: Option<Duration>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("users" / u64)
+            .and(warp::delete())
+            .and(auth(token))
+            .and(with_repo(repo))
+            .and_then(move |id, repo| with_timeout(timeout, handlers::delete_user(id, repo)))
+    }
+
+    pub fn log_level(
+        log_filter: Option<LogFilter>,
+        token: Option<String>,
+        body_limit: u64,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("log-level")
+            .and(warp::put())
+            .and(warp::any().and_then(move || {
+                let log_filter = log_filter.clone();
+                async move { log_filter.ok_or_else(warp::reject::not_found) }
+            }))
+            .and(auth(token))
+            .and(json_body(body_limit))
+            .and_then(|log_filter, level| catch_panics(handlers::log_level(log_filter, level)))
+    }
+
+    pub fn metrics(
+        exporter: impl MetricsExporter,
+        token: Option<String>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("metrics")
+            .and(warp::get())
+            .and(auth(token))
+            .and(warp::header::optional::<String>("accept"))
+            .and(with_exporter(exporter))
+            .and_then(|accept, exporter| catch_panics(handlers::metrics(accept, exporter)))
+    }
+
+    pub fn health() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("health")
+            .and(warp::get())
+            .and_then(|| catch_panics(handlers::health()))
+    }
+
+    pub fn version() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("version")
+            .and(warp::get())
+            .and_then(|| catch_panics(handlers::version()))
+    }
+
+    pub fn ready(
+        repo: impl Repository,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("ready")
+            .and(warp::get())
+            .and(with_repo(repo))
+            .and_then(|repo| catch_panics(handlers::ready(repo)))
+    }
+
+    pub fn trace_request() -> Trace<impl Fn(Info) -> Span + Clone> {
+        warp::trace(|info: Info| {
+            let span = tracing::info_span!(
+                "request",
+                remote.addr = Empty,
+                method = %info.method(),
+                path = %info.path(),
+                version = ?info.version(),
+                referer = Empty,
+                trace_id = Empty,
+                span_id = Empty,
+                request_id = Empty,
+                tenant.id = Empty,
+            );
+            if let Some(remote_addr) = info.remote_addr() {
+                span.record("remote.addr", display(remote_addr));
+            }
+            if let Some(referer) = info.referer() {
+                span.record("referer", display(referer));
+            }
+            // continue the caller's trace, if there is one
+            let mut cx = extract_context(info.request_headers());
+            if let Some(tenant) = tenant_id(info.request_headers()) {
+                span.record("tenant.id", tenant);
+                cx = with_tenant(cx, tenant);
+            }
+            span.set_parent(cx);
+            record_trace_context(&span);
+            span
+        })
+    }
+
+    const TENANT_ID: &str = "x-tenant-id";
+
+    fn tenant_id(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(TENANT_ID)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|tenant| !tenant.is_empty())
+    }
+
+    /// Rejects requests to the user routes without a tenant, if one is required.
+    fn require_tenant(
+        required: bool,
+    ) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::path::peek()
+            .and(warp::header::headers_cloned())
+            .and_then(move |path: Peek, headers: HeaderMap| async move {
+                let user_route = path.segments().next() == Some("users");
+                if required && user_route && tenant_id(&headers).is_none() {
+                    Err(warp::reject::custom(MissingTenant))
+                } else {
+                    Ok(())
+                }
+            })
+            .untuple_one()
+    }
+
+    // runs inside the request span, so the current span carries the trace id
+    fn with_trace_id(reply: impl Reply) -> Response {
+        let mut response = reply.into_response();
+        if let Some(trace_id) = current_trace_id() {
+            if let Ok(value) = HeaderValue::from_str(&trace_id) {
+                response.headers_mut().insert("x-trace-id", value);
+            }
+        }
+        response
+    }
+
+    const REQUEST_ID: &str = "x-request-id";
+
+    /// Takes the caller's `X-Request-Id` or generates one, and records it on the request span.
+    fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+        warp::header::headers_cloned().map(|headers: HeaderMap| {
+            // the id ends up in the logs, so only short printable ones are taken over
+            let request_id = headers
+                .get(REQUEST_ID)
+                .and_then(|value| value.to_str().ok())
+                .filter(|id| {
+                    (1..=128).contains(&id.len()) && id.chars().all(|c| c.is_ascii_graphic())
+                })
+                .map_or_else(|| Uuid::new_v4().to_string(), String::from);
+            Span::current().record("request_id", display(&request_id));
+            request_id
+        })
+    }
+
+    fn with_request_id(request_id: String, mut response: Response) -> Response {
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID, value);
+        }
+        response
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Encoding {
+        Gzip,
+        Deflate,
+    }
+
+    impl Encoding {
+        fn as_str(self) -> &'static str {
+            match self {
+                Self::Gzip => "gzip",
+                Self::Deflate => "deflate",
+            }
+        }
+    }
+
+    /// Picks the encoding for an `Accept-Encoding` header, gzip is preferred.
+    pub fn negotiate_encoding(accept_encoding: &str) -> Option<Encoding> {
+        let accepted: Vec<&str> = accept_encoding
+            .split(',')
+            .filter_map(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                let name = params.next()?;
+                let rejected = params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                (!rejected).then_some(name)
+            })
+            .collect();
+        [Encoding::Gzip, Encoding::Deflate]
+            .iter()
+            .copied()
+            .find(|encoding| {
+                accepted
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(encoding.as_str()))
+            })
+    }
+
+    // routes behind a disabled filter are not found
+    fn enabled(enabled: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::any()
+            .and_then(move || async move {
+                if enabled {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            })
+            .untuple_one()
+    }
+
+    // unlike header::optional, this never rejects the request
+    fn accept_encoding() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+        warp::header::headers_cloned().map(|headers: HeaderMap| {
+            headers
+                .get(ACCEPT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        })
+    }
+
+    /// Recovers from rejections with an error message in the language of `Accept-Language`.
+    fn recover_localized<T: Reply + Send>(
+        routes: impl Filter<Extract = (T,), Error = warp::Rejection> + Clone + Send + Sync,
+    ) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
+        let routes = routes
+            .map(|reply: T| Ok(reply.into_response()))
+            .or_else(|err| async move { Ok::<_, Infallible>((Err(err),)) });
+        warp::header::headers_cloned().and(routes).map(
+            |headers: HeaderMap, result: Result<Response, warp::Rejection>| {
+                result.unwrap_or_else(|err| {
+                    let accept_language = headers
+                        .get(ACCEPT_LANGUAGE)
+                        .and_then(|value| value.to_str().ok());
+                    handlers::localized_rejection(&err, accept_language)
+                })
+            },
+        )
+    }
+
+    fn content_length() -> impl Filter<Extract = (Option<u64>,), Error = Infallible> + Clone {
+        warp::header::headers_cloned().map(|headers: HeaderMap| {
+            headers
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+        })
+    }
+
+    /// Compresses the body on the fly, if the client accepts a supported encoding.
+    fn compress(accept_encoding: Option<String>, response: Response) -> Response {
+        let encoding = match accept_encoding.as_deref().and_then(negotiate_encoding) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+        // compressing would buffer server-sent events until the encoder flushes
+        if response.headers().contains_key(CONTENT_ENCODING)
+            || response
+                .headers()
+                .get(CONTENT_TYPE)
+                .is_some_and(|media_type| media_type == "text/event-stream")
+            || response.body().size_hint().exact() == Some(0)
+        {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        let body = TryStreamExt::map_err(body, io::Error::other);
+        let reader = StreamReader::new(body);
+        let body = match encoding {
+            Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+            Encoding::Deflate => Body::wrap_stream(ReaderStream::new(DeflateEncoder::new(reader))),
+        };
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+        Response::from_parts(parts, body)
+    }
+
+    fn with_repo(
+        repo: impl Repository,
+    ) -> impl Filter<Extract = (impl Repository,), Error = Infallible> + Clone {
+        warp::any().map(move || repo.clone())
+    }
+
+    fn with_cors(
+        routes: impl Filter<Extract = (Response,), Error = Infallible> + Clone + Send + Sync + 'static,
+        origins: Option<&CorsOrigins>,
+    ) -> BoxedFilter<(Response,)> {
+        let origins = match origins {
+            Some(origins) => origins,
+            None => return routes.boxed(),
+        };
+        let cors = warp::cors()
+            .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
+            .allow_headers(vec![
+                "content-type",
+                "authorization",
+                "idempotency-key",
+                "if-match",
+                "x-request-id",
+                "x-tenant-id",
+            ])
+            .expose_headers(vec![
+                "x-request-id",
+                "x-trace-id",
+                "etag",
+                "x-total-count",
+                "location",
+                "last-modified",
+                "retry-after",
+            ]);
+        let cors = match origins {
+            CorsOrigins::Any => cors.allow_any_origin(),
+            CorsOrigins::List(origins) => cors.allow_origins(origins.iter().map(String::as_str)),
+        };
+        warp::method()
+            .and(routes.with(cors))
+            .map(|method: Method, reply| {
+                let mut response = Reply::into_response(reply);
+                // there are no OPTIONS routes, so a successful one was answered as a preflight
+                if method == Method::OPTIONS && response.status() == StatusCode::OK {
+                    *response.status_mut() = StatusCode::NO_CONTENT;
+                }
+                response
+            })
+            .boxed()
+    }
+
+    /// Rejects with a timeout if the handler takes longer than the configured time.
+    async fn with_timeout<T>(
+        timeout: Option<Duration>,
+        handler: impl Future<Output = Result<T, warp::Rejection>>,
+    ) -> Result<T, warp::Rejection> {
+        let handler = catch_panics(handler);
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handler)
+                .await
+                .unwrap_or_else(|_| Err(warp::reject::custom(Timeout))),
+            None => handler.await,
+        }
+    }
+
+    /// Turns a panic of the handler into a rejection, instead of dropping the connection.
+    ///
+    /// warp 0.3 seals `Filter`, so the future of the combined routes can't be wrapped, and
+    /// serving them through `warp::service` would lose the remote address. Every route
+    /// therefore runs its handler through this instead.
+    pub async fn catch_panics<T, E: Into<warp::Rejection>>(
+        handler: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, warp::Rejection> {
+        AssertUnwindSafe(handler)
+            .catch_unwind()
+            .await
+            .map(|result| result.map_err(Into::into))
+            .unwrap_or_else(|panic| {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown");
+                tracing::error!(panic = message, "handler panicked");
+                record_panic();
+                Err(warp::reject::custom(Panicked))
+            })
+    }
+
+    /// Requires `Authorization: Bearer <token>` if a token is configured.
+    pub fn auth(
+        token: Option<String>,
+    ) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("authorization")
+            .and_then(move |header: Option<String>| {
+                let token = token.clone();
+                async move {
+                    let expected = match token {
+                        Some(token) => token,
+                        None => return Ok(()),
+                    };
+                    let presented = header
+                        .as_deref()
+                        .and_then(|header| header.strip_prefix("Bearer "))
+                        .ok_or_else(|| warp::reject::custom(Unauthorized))?;
+                    if constant_time_eq(presented.as_bytes(), expected.as_bytes()) {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Forbidden))
+                    }
+                }
+            })
+            .untuple_one()
+    }
+
+    /// Token buckets per client IP, refilled continuously.
+    #[derive(Clone)]
+    pub struct RateLimiter {
+        capacity: f64,
+        per_second: f64,
+        buckets: Arc<Mutex<HashMap<Option<IpAddr>, Bucket>>>,
+    }
+
+    struct Bucket {
+        tokens: f64,
+        updated: Instant,
+    }
+
+    impl RateLimiter {
+        // buckets which refilled completely are dropped once there are more than this
+        const MAX_BUCKETS: usize = 10_000;
+
+        pub fn per_minute(limit: u32) -> Self {
+            Self {
+                capacity: limit.into(),
+                per_second: f64::from(limit) / 60.,
+                buckets: Arc::default(),
+            }
+        }
+
+        /// Takes a token from the client's bucket, or returns how long to wait for the next one.
+        fn acquire(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().unwrap();
+            if buckets.len() > Self::MAX_BUCKETS {
+                let capacity = self.capacity;
+                let per_second = self.per_second;
+                buckets.retain(|_, bucket| {
+                    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                    bucket.tokens + elapsed * per_second < capacity
+                });
+            }
+            let bucket = buckets.entry(ip).or_insert(Bucket {
+                tokens: self.capacity,
+                updated: now,
+            });
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.capacity);
+            bucket.updated = now;
+            if bucket.tokens >= 1. {
+                bucket.tokens -= 1.;
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64(
+                    (1. - bucket.tokens) / self.per_second,
+                ))
+            }
+        }
+    }
+
+    pub fn rate_limit(
+        limiter: Option<RateLimiter>,
+    ) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::addr::remote()
+            .and_then(move |remote: Option<SocketAddr>| {
+                let result = match &limiter {
+                    Some(limiter) => limiter.acquire(remote.map(|remote| remote.ip())),
+                    None => Ok(()),
+                };
+                async move {
+                    result.map_err(|retry_after| {
+                        let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                        warp::reject::custom(TooManyRequests { retry_after })
+                    })
+                }
+            })
+            .untuple_one()
+    }
+
+    /// Sheds the request if all permits are taken, rather than waiting for one.
+    pub fn concurrency_limit(
+        semaphore: Option<Arc<Semaphore>>,
+    ) -> impl Filter<Extract = (Option<OwnedSemaphorePermit>,), Error = warp::Rejection> + Clone
+    {
+        warp::any().and_then(move || {
+            let result = match &semaphore {
+                Some(semaphore) => semaphore
+                    .clone()
+                    .try_acquire_owned()
+                    .map(Some)
+                    .map_err(|_| warp::reject::custom(Overloaded)),
+                None => Ok(None),
+            };
+            async move { result }
+        })
+    }
+
+    // compares every byte, so the time taken doesn't reveal the length of a matching prefix
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    fn with_exporter(
+        exporter: impl MetricsExporter,
+    ) -> impl Filter<Extract = (impl MetricsExporter,), Error = Infallible> + Clone {
+        warp::any().map(move || exporter.clone())
+    }
+
+    fn query<T: DeserializeOwned + Send + 'static>(
+    ) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+        warp::query::raw()
+            .or(warp::any().map(String::new))
+            .unify()
+            .and_then(|query: String| async move {
+                serde_urlencoded::from_str(&query)
+                    .map_err(|err| warp::reject::custom(InvalidQuery(err.to_string())))
+            })
+    }
+
+    fn user_filter() -> impl Filter<Extract = (UserFilter,), Error = warp::Rejection> + Clone {
+        query::<UserFilter>().and_then(|filter: UserFilter| async move {
+            filter
+                .validate()
+                .map(|_| filter)
+                .map_err(|reason| warp::reject::custom(InvalidQuery(reason)))
+        })
+    }
+
+    const DEFAULT_BODY_LIMIT: u64 = 1024 * 16;
+
+    const MERGE_PATCH: &str = "application/merge-patch+json";
+    const JSON_PATCH: &str = "application/json-patch+json";
+
+    // routes for other media types are tried as well, so a mismatch is only a not found
+    fn content_type(
+        media_type: &'static str,
+    ) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("content-type")
+            .and_then(move |content_type: Option<String>| async move {
+                let essence = content_type
+                    .as_deref()
+                    .and_then(|content_type| content_type.split(';').next())
+                    .map(str::trim);
+                match essence {
+                    Some(essence) if essence.eq_ignore_ascii_case(media_type) => Ok(()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            })
+            .untuple_one()
+    }
+
+    // warp::body::json only accepts application/json
+    fn raw_json_body(
+        limit: u64,
+    ) -> impl Filter<Extract = (serde_json::Value,), Error = warp::Rejection> + Clone {
+        warp::body::content_length_limit(limit)
+            .and(warp::body::bytes())
+            .and_then(|body: Bytes| async move {
+                serde_json::from_slice(&body)
+                    .map_err(|err| warp::reject::custom(InvalidBody(err.to_string())))
+            })
+    }
+
+    fn json_body<T: DeserializeOwned + Send>(
+        limit: u64,
+    ) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+        warp::body::content_length_limit(limit).and(warp::body::json())
+    }
+}
+
+mod handlers {
+    use super::models::{
+        merge_patch, FieldSelection, NewUser, Pagination, Repository, RepositoryError,
+        SearchRequest, SearchResult, Sorting, User, UserFilter, UserPatch, ValidationError,
+    };
+    use super::observability::{
+        record_error, record_user_deleted, record_users_created, record_users_replaced, LogFilter,
+        MetricsExporter,
+    };
+    use chrono::DateTime;
+    use futures::stream::{self, StreamExt};
+    use serde::{Deserialize, Serialize};
+    use std::borrow::Cow;
+    use std::cmp::Ordering;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{HashMap, HashSet};
+    use std::convert::Infallible;
+    use std::error::Error;
+    use std::hash::{Hash, Hasher};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::broadcast;
+    use tokio::sync::broadcast::error::RecvError;
+    use tracing::field::Empty;
+    use tracing::{instrument, Level, Span};
+    use warp::body::BodyDeserializeError;
+    use warp::cors::CorsForbidden;
+    use warp::http::header::{
+        ALLOW, CONTENT_TYPE, ETAG, LAST_MODIFIED, LOCATION, RETRY_AFTER, WWW_AUTHENTICATE,
+    };
+    use warp::http::{HeaderValue, StatusCode};
+    use warp::hyper::Body;
+    use warp::reject::{
+        InvalidHeader, LengthRequired, MethodNotAllowed, MissingHeader, PayloadTooLarge, Reject,
+        UnsupportedMediaType,
+    };
+    use warp::reply::Response;
+    use warp::sse::Event;
+    use warp::{Rejection, Reply};
+
+    #[derive(Debug)]
+    pub struct NotFound;
+
+    impl Reject for NotFound {}
+
+    #[derive(Debug)]
+    pub struct Conflict;
+
+    impl Reject for Conflict {}
+
+    #[derive(Debug)]
+    pub struct StorageFailure;
+
+    impl Reject for StorageFailure {}
+
+    #[derive(Debug)]
+    pub struct IdMismatch;
+
+    impl Reject for IdMismatch {}
+
+    #[derive(Debug)]
+    pub struct PreconditionRequired;
+
+    impl Reject for PreconditionRequired {}
+
+    #[derive(Debug)]
+    pub struct VersionConflict;
+
+    impl Reject for VersionConflict {}
+
+    #[derive(Debug)]
+    pub struct Invalid(pub Vec<ValidationError>);
+
+    impl Reject for Invalid {}
+
+    // the events end up on the handler's span, which explains the rejection in a trace
+    fn check(result: Result<(), Vec<ValidationError>>) -> Result<(), Rejection> {
+        result.map_err(|errors| {
+            for error in &errors {
+                tracing::event!(
+                    Level::WARN,
+                    field = error.field,
+                    reason = error.reason,
+                    "validation failed"
+                );
+            }
+            warp::reject::custom(Invalid(errors))
+        })
+    }
+
+    #[derive(Debug)]
+    pub struct InvalidQuery(pub String);
+
+    #[derive(Debug)]
+    pub struct InvalidBody(pub String);
+
+    impl Reject for InvalidBody {}
+
+    #[derive(Debug)]
+    pub struct InvalidPatch(pub String);
+
+    impl Reject for InvalidPatch {}
+
+    #[derive(Debug)]
+    pub struct Unauthorized;
+
+    impl Reject for Unauthorized {}
+
+    #[derive(Debug)]
+    pub struct MissingTenant;
+
+    impl Reject for MissingTenant {}
+
+    #[derive(Debug)]
+    pub struct Forbidden;
+
+    impl Reject for Forbidden {}
+
+    #[derive(Debug)]
+    pub struct IdempotencyKeyReused;
+
+    impl Reject for IdempotencyKeyReused {}
+
+    #[derive(Debug)]
+    pub struct IdempotencyKeyInUse;
+
+    impl Reject for IdempotencyKeyInUse {}
+
+    #[derive(Debug)]
+    pub struct InvalidDirective(pub String);
+
+    impl Reject for InvalidDirective {}
+
+    #[derive(Debug)]
+    pub struct Timeout;
+
+    impl Reject for Timeout {}
+
+    #[derive(Debug)]
+    pub struct Panicked;
+
+    impl Reject for Panicked {}
+
+    #[derive(Debug)]
+    pub struct Overloaded;
+
+    impl Reject for Overloaded {}
+
+    /// The path is routed, but not for the method of the request.
+    #[derive(Debug)]
+    pub struct UnsupportedMethod {
+        pub allow: &'static str,
+    }
+
+    impl Reject for UnsupportedMethod {}
+
+    #[derive(Debug)]
+    pub struct TooManyRequests {
+        pub retry_after: u64,
+    }
+
+    impl Reject for TooManyRequests {}
+
+    impl Reject for InvalidQuery {}
+
+    fn reject(err: RepositoryError) -> Rejection {
+        match err {
+            RepositoryError::NotFound => warp::reject::custom(NotFound),
+            RepositoryError::Conflict => warp::reject::custom(Conflict),
+            RepositoryError::Stale => warp::reject::custom(VersionConflict),
+            RepositoryError::Storage(reason) => {
+                tracing::error!(%reason, "storage failure");
+                warp::reject::custom(StorageFailure)
+            }
+        }
+    }
+
+    #[instrument(skip(repo), fields(users.count = Empty))]
+    pub async fn list_users(
+        pagination: Pagination,
+        filter: UserFilter,
+        sorting: Sorting,
+        selection: FieldSelection,
+        accept: Option<String>,
+        if_none_match: Option<String>,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let search = SearchRequest {
+            filter,
+            sorting,
+            pagination,
+        };
+        let SearchResult { total, users: page } = search.run(repo.list().await.map_err(reject)?);
+        Span::current().record("users.count", page.len());
+        let media_type = [NDJSON, CSV]
+            .iter()
+            .copied()
+            .find(|media_type| accepts(accept.as_deref(), media_type))
+            .unwrap_or(JSON);
+        // CSV has a fixed set of columns, so the selection only applies to JSON and NDJSON
+        let fields = selection.fields.filter(|_| media_type != CSV);
+        let etag = match &fields {
+            Some(fields) => etag(&page, &format!("{}; fields={:?}", media_type, fields)),
+            None => etag(&page, media_type),
+        };
+        let mut response = if if_none_match.is_some_and(|tags| matches_etag(&tags, &etag)) {
+            let mut response = Response::default();
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            response
+        } else if let Some(fields) = fields {
+            let selected: Vec<_> = page.iter().map(|user| fields.select(user)).collect();
+            if media_type == NDJSON {
+                ndjson(selected)
+            } else {
+                warp::reply::json(&selected).into_response()
+            }
+        } else if media_type == NDJSON {
+            ndjson(page)
+        } else if media_type == CSV {
+            let mut response = Response::new(Body::from(csv(&page)));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(CSV));
+            response
+        } else {
+            warp::reply::json(&page).into_response()
+        };
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            response.headers_mut().insert(ETAG, value);
+        }
+        response
+            .headers_mut()
+            .insert("x-total-count", HeaderValue::from(total));
+        Ok(response)
+    }
+
+    /// Computes a weak ETag, which doesn't depend on the order of the users.
+    pub fn etag(users: &[User], media_type: &str) -> String {
+        let combined = users
+            .iter()
+            .map(|user| {
+                let mut hasher = DefaultHasher::new();
+                // users are always serializable, as the json reply relies on as well
+                serde_json::to_vec(user)
+                    .unwrap_or_default()
+                    .hash(&mut hasher);
+                hasher.finish()
+            })
+            .fold(0u64, u64::wrapping_add);
+        let mut hasher = DefaultHasher::new();
+        (combined, users.len(), media_type).hash(&mut hasher);
+        format!("W/\"{:016x}\"", hasher.finish())
+    }
+
+    // If-None-Match uses the weak comparison, so the W/ prefix is ignored on both sides
+    fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+        let etag = opaque(etag);
+        if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+    }
+
+    const JSON: &str = "application/json";
+    const NDJSON: &str = "application/x-ndjson";
+    const CSV: &str = "text/csv";
+
+    fn accepts(accept: Option<&str>, media_type: &str) -> bool {
+        accept.is_some_and(|accept| {
+            accept.split(',').any(|range| {
+                let range = range.split(';').next().unwrap_or_default();
+                range.trim().eq_ignore_ascii_case(media_type)
+            })
+        })
+    }
+
+    // one JSON document per line, serialized as the body is polled
+    fn ndjson<T: Serialize + Send + 'static>(items: Vec<T>) -> Response {
+        let lines = stream::iter(items).map(|item| {
+            let mut line = serde_json::to_vec(&item)?;
+            line.push(b'\n');
+            Ok::<_, serde_json::Error>(line)
+        });
+        let mut response = Response::new(Body::wrap_stream(lines));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(NDJSON));
+        response
+    }
+
+    /// Renders the users as CSV with a header row, quoting fields as in RFC 4180.
+    pub fn csv(users: &[User]) -> String {
+        fn field(value: &str) -> Cow<'_, str> {
+            if value.contains(&[',', '"', '\r', '\n'][..]) {
+                format!("\"{}\"", value.replace('"', "\"\"")).into()
+            } else {
+                value.into()
+            }
+        }
+        let mut csv = String::from("id,firstName,lastName,gender\r\n");
+        for user in users {
+            csv.push_str(&format!(
+                "{},{},{},{}\r\n",
+                user.id,
+                field(user.first_name.as_deref().unwrap_or_default()),
+                field(&user.last_name),
+                field(user.gender.as_str()),
+            ));
+        }
+        csv
+    }
+
+    #[instrument(skip(idempotency, events, repo))]
+    pub async fn create_user(
+        new_user: NewUser,
+        idempotency_key: Option<String>,
+        idempotency: IdempotencyCache,
+        events: UserEvents,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        check(new_user.validate())?;
+        let reservation = match idempotency_key {
+            Some(key) => match idempotency.begin(key, &new_user)? {
+                Idempotent::Replay(user) => {
+                    let mut response = created(&user);
+                    response
+                        .headers_mut()
+                        .insert("idempotent-replayed", HeaderValue::from_static("true"));
+                    return Ok(response);
+                }
+                Idempotent::Reserved(reservation) => Some(reservation),
+            },
+            None => None,
+        };
+        let user = repo.create(new_user).await.map_err(reject)?;
+        record_users_created(1);
+        events.publish(&user);
+        if let Some(reservation) = reservation {
+            reservation.complete(user.clone());
+        }
+        Ok(created(&user))
+    }
+
+    /// Broadcasts created users to the subscribers of `/users/events`.
+    #[derive(Clone)]
+    pub struct UserEvents {
+        sender: broadcast::Sender<User>,
+    }
+
+    impl Default for UserEvents {
+        fn default() -> Self {
+            let (sender, _) = broadcast::channel(UserEvents::CAPACITY);
+            UserEvents { sender }
+        }
+    }
+
+    impl UserEvents {
+        // subscribers lagging further behind skip the missed users
+        const CAPACITY: usize = 64;
+
+        fn publish(&self, user: &User) {
+            // an error only means nobody is subscribed
+            let _ = self.sender.send(user.clone());
+        }
+    }
+
+    /// Streams users created after subscribing as server-sent events.
+    pub fn user_events(events: &UserEvents) -> impl warp::Reply {
+        let receiver = events.sender.subscribe();
+        let events = stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(user) => {
+                        let event = Event::default().event("created").json_data(&user);
+                        return Some((event, receiver));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "user events subscriber lagged behind");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        warp::sse::reply(warp::sse::keep_alive().stream(events))
+    }
+
+    fn created(user: &User) -> Response {
+        let reply = warp::reply::json(user);
+        let reply = warp::reply::with_header(reply, LOCATION, format!("/users/{}", user.id));
+        warp::reply::with_status(reply, StatusCode::CREATED).into_response()
+    }
+
+    /// Remembers created users by `Idempotency-Key`, so retried requests are replayed.
+    #[derive(Clone, Default)]
+    pub struct IdempotencyCache {
+        entries: Arc<Mutex<HashMap<String, Entry>>>,
+    }
+
+    struct Entry {
+        request: NewUser,
+        started: Instant,
+        // unset while the first request is still in progress
+        user: Option<User>,
+    }
+
+    pub enum Idempotent {
+        Replay(User),
+        Reserved(Reservation),
+    }
+
+    impl IdempotencyCache {
+        const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+        const MAX_ENTRIES: usize = 10_000;
+
+        fn begin(&self, key: String, request: &NewUser) -> Result<Idempotent, Rejection> {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() > Self::MAX_ENTRIES {
+                entries.retain(|_, entry| entry.started.elapsed() < Self::TTL);
+            }
+            if let Some(entry) = entries.get(&key) {
+                if entry.started.elapsed() < Self::TTL {
+                    if entry.request != *request {
+                        return Err(warp::reject::custom(IdempotencyKeyReused));
+                    }
+                    return match &entry.user {
+                        Some(user) => Ok(Idempotent::Replay(user.clone())),
+                        None => Err(warp::reject::custom(IdempotencyKeyInUse)),
+                    };
+                }
+            }
+            entries.insert(
+                key.clone(),
+                Entry {
+                    request: request.clone(),
+                    started: Instant::now(),
+                    user: None,
+                },
+            );
+            Ok(Idempotent::Reserved(Reservation {
+                cache: self.clone(),
+                key,
+                completed: false,
+            }))
+        }
+    }
+
+    /// Releases the key again if the request fails or is dropped before completion.
+    pub struct Reservation {
+        cache: IdempotencyCache,
+        key: String,
+        completed: bool,
+    }
+
+    impl Reservation {
+        fn complete(mut self, user: User) {
+            let mut entries = self.cache.entries.lock().unwrap();
+            if let Some(entry) = entries.get_mut(&self.key) {
+                entry.user = Some(user);
+            }
+            self.completed = true;
+        }
+    }
+
+    impl Drop for Reservation {
+        fn drop(&mut self) {
+            if !self.completed {
+                self.cache.entries.lock().unwrap().remove(&self.key);
+            }
+        }
+    }
+
+    #[instrument(skip(events, repo))]
+    pub async fn create_users_bulk(
+        new_users: Vec<NewUser>,
+        events: UserEvents,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        check(new_users.iter().try_for_each(NewUser::validate))?;
+        let users = repo.create_many(new_users).await.map_err(reject)?;
+        record_users_created(users.len());
+        users.iter().for_each(|user| events.publish(user));
+        let reply = warp::reply::json(&users);
+        Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+    }
+
+    /// Imports the users with their ids, replacing all stored users.
+    #[instrument(skip(users, repo), fields(users.count = users.len()))]
+    pub async fn replace_users(
+        mut users: Vec<User>,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        check(users.iter().try_for_each(User::validate))?;
+        let mut ids = HashSet::new();
+        if !users.iter().all(|user| ids.insert(user.id)) {
+            check(Err(vec![ValidationError {
+                field: "id",
+                reason: "must be unique",
+            }]))?;
+        }
+        for user in &mut users {
+            // imported users start over, like created ones
+            user.version = 1;
+        }
+        let previous = active_count(&repo.list().await.map_err(reject)?);
+        let users = repo.replace_all(users).await.map_err(reject)?;
+        record_users_replaced(active_count(&users) as i64 - previous as i64);
+        Ok(warp::reply::json(&users))
+    }
+
+    fn active_count(users: &[User]) -> usize {
+        users
+            .iter()
+            .filter(|user| user.deleted_at.is_none())
+            .count()
+    }
+
+    #[instrument(skip(repo), fields(users.count = Empty))]
+    pub async fn search_users(
+        search: SearchRequest,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        search
+            .filter
+            .validate()
+            .map_err(|reason| warp::reject::custom(InvalidBody(reason)))?;
+        let result = search.run(repo.list().await.map_err(reject)?);
+        Span::current().record("users.count", result.users.len());
+        let reply = warp::reply::json(&result.users);
+        Ok(warp::reply::with_header(
+            reply,
+            "x-total-count",
+            result.total,
+        ))
+    }
+
+    #[derive(Serialize)]
+    struct Count {
+        count: usize,
+    }
+
+    #[instrument(skip(repo))]
+    pub async fn count_users(
+        filter: UserFilter,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let users = repo.list().await.map_err(reject)?;
+        let count = users.iter().filter(|user| filter.matches(user)).count();
+        Ok(warp::reply::json(&Count { count }))
+    }
+
+    #[instrument(skip(repo))]
+    pub async fn get_user(
+        id: u64,
+        if_modified_since: Option<String>,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let user = repo.get(id).await.map_err(reject)?;
+        let mut response =
+            if if_modified_since.is_some_and(|since| not_modified_since(&since, &user)) {
+                let mut response = Response::default();
+                *response.status_mut() = StatusCode::NOT_MODIFIED;
+                response
+            } else {
+                warp::reply::json(&user).into_response()
+            };
+        let last_modified = user.updated_at.format(HTTP_DATE).to_string();
+        if let Ok(value) = HeaderValue::from_str(&last_modified) {
+            response.headers_mut().insert(LAST_MODIFIED, value);
+        }
+        Ok(response)
+    }
+
+    const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+    // HTTP dates have a resolution of seconds, a date that can't be parsed is ignored
+    fn not_modified_since(if_modified_since: &str, user: &User) -> bool {
+        DateTime::parse_from_rfc2822(if_modified_since.trim())
+            .map(|since| user.updated_at.timestamp() <= since.timestamp())
+            .unwrap_or(false)
+    }
+
+    #[instrument(skip(repo))]
+    pub async fn replace_user(
+        id: u64,
+        mut user: User,
+        if_match: Option<String>,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        if user.id != id {
+            return Err(warp::reject::custom(IdMismatch));
+        }
+        let version = if_match_version(if_match)?;
+        check(user.validate())?;
+        user.version = version;
+        user.touch();
+        let user = repo.update(user).await.map_err(reject)?;
+        Ok(warp::reply::json(&user))
+    }
+
+    #[instrument(skip(repo))]
+    pub async fn patch_user(
+        id: u64,
+        patch: UserPatch,
+        if_match: Option<String>,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let version = if_match_version(if_match)?;
+        let mut user = repo.get(id).await.map_err(reject)?;
+        user.apply(patch);
+        check(user.validate())?;
+        user.version = version;
+        user.touch();
+        let user = repo.update(user).await.map_err(reject)?;
+        Ok(warp::reply::json(&user))
+    }
+
+    #[instrument(skip(patch, repo))]
+    pub async fn merge_patch_user(
+        id: u64,
+        patch: serde_json::Value,
+        if_match: Option<String>,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        edit_user(id, if_match, repo, |document| {
+            merge_patch(document, &patch);
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip(patch, repo))]
+    pub async fn json_patch_user(
+        id: u64,
+        patch: serde_json::Value,
+        if_match: Option<String>,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let patch: json_patch::Patch = serde_json::from_value(patch)
+            .map_err(|err| warp::reject::custom(InvalidBody(err.to_string())))?;
+        edit_user(id, if_match, repo, |document| {
+            // the error names the index and path of the failing operation
+            json_patch::patch(document, &patch.0).map_err(|err| err.to_string())
+        })
+        .await
+    }
+
+    /// Updates the user by editing its JSON representation.
+    async fn edit_user(
+        id: u64,
+        if_match: Option<String>,
+        repo: impl Repository,
+        edit: impl FnOnce(&mut serde_json::Value) -> Result<(), String>,
+    ) -> Result<warp::reply::Json, Rejection> {
+        let invalid = |reason: String| warp::reject::custom(InvalidPatch(reason));
+        let version = if_match_version(if_match)?;
+        let stored = repo.get(id).await.map_err(reject)?;
+        let mut document = serde_json::to_value(&stored).map_err(|err| invalid(err.to_string()))?;
+        edit(&mut document).map_err(invalid)?;
+        let mut user: User =
+            serde_json::from_value(document).map_err(|err| invalid(err.to_string()))?;
+        if user.id != id {
+            return Err(warp::reject::custom(IdMismatch));
+        }
+        check(user.validate())?;
+        user.version = version;
+        user.touch();
+        let user = repo.update(user).await.map_err(reject)?;
+        Ok(warp::reply::json(&user))
+    }
+
+    /// Parses the version an update is based on, from `If-Match: 3` or `If-Match: "3"`.
+    fn if_match_version(if_match: Option<String>) -> Result<u64, Rejection> {
+        let if_match = if_match.ok_or_else(|| warp::reject::custom(PreconditionRequired))?;
+        // a tag which is not a version can never match the current one
+        if_match
+            .trim()
+            .trim_matches('"')
+            .parse()
+            .map_err(|_| warp::reject::custom(VersionConflict))
+    }
+
+    #[instrument(skip(repo))]
+    pub async fn delete_user(
+        id: u64,
+        repo: impl Repository,
+    ) -> Result<impl warp::Reply, Rejection> {
+        repo.delete(id).await.map_err(reject)?;
+        record_user_deleted();
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    const OPENMETRICS: &str = "application/openmetrics-text";
+
+    pub async fn metrics(
+        accept: Option<String>,
+        exporter: impl MetricsExporter,
+    ) -> Result<impl warp::Reply, Infallible> {
+        if accepts(accept.as_deref(), OPENMETRICS) {
+            if let Some(buf) = exporter.export_openmetrics() {
+                let content_type = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+                return Ok(
+                    warp::reply::with_header(buf, CONTENT_TYPE, content_type).into_response()
+                );
+            }
+        }
+        let buf = exporter.export();
+        let content_type = exporter.content_type();
+        Ok(warp::reply::with_header(buf, CONTENT_TYPE, content_type).into_response())
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LogLevel {
+        directive: String,
+    }
+
+    #[instrument(skip(log_filter))]
+    pub async fn log_level(
+        log_filter: LogFilter,
+        level: LogLevel,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log_filter
+            .reload(&level.directive)
+            .map_err(|reason| warp::reject::custom(InvalidDirective(reason)))?;
+        tracing::info!(directive = %level.directive, "log level changed");
+        Ok(warp::reply::json(&level))
+    }
+
+    #[derive(Serialize)]
+    struct Health {
+        status: &'static str,
+    }
+
+    pub async fn health() -> Result<impl warp::Reply, Infallible> {
+        Ok(warp::reply::json(&Health { status: "ok" }))
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Version {
+        name: &'static str,
+        version: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        git_sha: Option<&'static str>,
+        features: Vec<&'static str>,
+    }
+
+    pub async fn version() -> Result<impl warp::Reply, Infallible> {
+        Ok(warp::reply::json(&Version {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("GIT_SHA"),
+            features: features(),
+        }))
+    }
+
+    /// The optional Cargo features compiled into the binary.
+    fn features() -> Vec<&'static str> {
+        [
+            ("jaeger", cfg!(feature = "jaeger")),
+            ("otlp", cfg!(feature = "otlp")),
+            ("sqlite", cfg!(feature = "sqlite")),
+            ("stdout", cfg!(feature = "stdout")),
+            ("tls", cfg!(feature = "tls")),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| *feature)
+        .collect()
+    }
+
+    #[derive(Serialize)]
+    struct Readiness {
+        status: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dependency: Option<&'static str>,
+    }
+
+    pub async fn ready(repo: impl Repository) -> Result<impl warp::Reply, Infallible> {
+        let (status, readiness) = match repo.ping().await {
+            Ok(()) => (
+                StatusCode::OK,
+                Readiness {
+                    status: "ready",
+                    dependency: None,
+                },
+            ),
+            Err(err) => {
+                tracing::warn!(?err, "storage is not ready");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Readiness {
+                        status: "unavailable",
+                        dependency: Some("storage"),
+                    },
+                )
+            }
+        };
+        Ok(warp::reply::with_status(
+            warp::reply::json(&readiness),
+            status,
+        ))
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct ApiError {
+        #[serde(skip)]
+        pub status: StatusCode,
+        pub code: &'static str,
+        pub message: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub details: Vec<ValidationError>,
+        /// Seconds after which the request may be retried.
+        #[serde(skip)]
+        pub retry_after: Option<u64>,
+        /// Methods supported by the path, if the one of the request isn't.
+        #[serde(skip)]
+        pub allow: Option<&'static str>,
+    }
+
+    impl ApiError {
+        fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+            Self {
+                status,
+                code,
+                message: message.into(),
+                details: Vec::new(),
+                retry_after: None,
+                allow: None,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Language {
+        En,
+        De,
+    }
+
+    impl Language {
+        /// Picks the supported language with the highest quality, English is the fallback.
+        pub fn negotiate(accept_language: Option<&str>) -> Self {
+            let mut ranges: Vec<(&str, f32)> = accept_language
+                .unwrap_or_default()
+                .split(',')
+                .map(|range| {
+                    let mut params = range.split(';');
+                    let tag = params.next().unwrap_or_default().trim();
+                    let quality = params
+                        .filter_map(|param| param.trim().strip_prefix("q="))
+                        .find_map(|q| q.trim().parse().ok())
+                        .unwrap_or(1.0);
+                    (tag, quality)
+                })
+                .filter(|(_, quality)| *quality > 0.0)
+                .collect();
+            // stable, so ranges of equal quality keep the client's order
+            ranges.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+            ranges
+                .iter()
+                .find_map(|(tag, _)| {
+                    let primary = tag.split('-').next().unwrap_or_default();
+                    if primary.eq_ignore_ascii_case("de") {
+                        Some(Language::De)
+                    } else if primary.eq_ignore_ascii_case("en") {
+                        Some(Language::En)
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(Language::En)
+        }
+    }
+
+    /// German messages by error code, matched against the start of the English message, so a
+    /// reason appended to it is kept and codes with several messages are told apart.
+    const GERMAN_MESSAGES: &[(&str, &str, &str)] = &[
+        ("not_found", "user not found", "Benutzer nicht gefunden"),
+        ("not_found", "route not found", "Route nicht gefunden"),
+        (
+            "conflict",
+            "user id already exists",
+            "Benutzer-ID existiert bereits",
+        ),
+        (
+            "storage",
+            "internal storage error",
+            "interner Speicherfehler",
+        ),
+        (
+            "validation",
+            "validation failed",
+            "Validierung fehlgeschlagen",
+        ),
+        (
+            "version_conflict",
+            "user was modified in the meantime",
+            "Benutzer wurde zwischenzeitlich geändert",
+        ),
+        (
+            "precondition_required",
+            "missing If-Match header with the user version",
+            "If-Match-Header mit der Benutzerversion fehlt",
+        ),
+        (
+            "id_mismatch",
+            "id in path and body differ",
+            "ID in Pfad und Body unterscheiden sich",
+        ),
+        (
+            "bad_json",
+            "invalid request body",
+            "ungültiger Request-Body",
+        ),
+        (
+            "invalid_patch",
+            "patch can't be applied",
+            "Patch kann nicht angewendet werden",
+        ),
+        ("invalid_query", "invalid query", "ungültige Query"),
+        (
+            "missing_tenant",
+            "missing X-Tenant-Id header",
+            "X-Tenant-Id-Header fehlt",
+        ),
+        ("unauthorized", "missing bearer token", "Bearer-Token fehlt"),
+        (
+            "cors_forbidden",
+            "cross-origin request not allowed",
+            "Cross-Origin-Request nicht erlaubt",
+        ),
+        (
+            "forbidden",
+            "invalid bearer token",
+            "ungültiges Bearer-Token",
+        ),
+        (
+            "idempotency_key_reused",
+            "idempotency key was used for a different request",
+            "Idempotency-Key wurde für einen anderen Request verwendet",
+        ),
+        (
+            "idempotency_key_in_use",
+            "a request with this idempotency key is in progress",
+            "ein Request mit diesem Idempotency-Key ist in Bearbeitung",
+        ),
+        (
+            "invalid_directive",
+            "invalid log directive",
+            "ungültige Log-Direktive",
+        ),
+        ("internal", "internal server error", "interner Serverfehler"),
+        (
+            "timeout",
+            "request took too long",
+            "Request hat zu lange gedauert",
+        ),
+        ("rate_limited", "too many requests", "zu viele Requests"),
+        (
+            "overloaded",
+            "too many concurrent requests",
+            "zu viele gleichzeitige Requests",
+        ),
+        ("too_large", "payload too large", "Payload zu groß"),
+        (
+            "unsupported_media_type",
+            "unsupported media type",
+            "nicht unterstützter Medientyp",
+        ),
+        (
+            "length_required",
+            "content-length header required",
+            "Content-Length-Header erforderlich",
+        ),
+        (
+            "method_not_allowed",
+            "method not allowed",
+            "Methode nicht erlaubt",
+        ),
+        (
+            "bad_request",
+            "invalid request headers",
+            "ungültige Request-Header",
+        ),
+    ];
+
+    impl ApiError {
+        fn localize(mut self, language: Language) -> Self {
+            if language == Language::De {
+                let translation = GERMAN_MESSAGES.iter().find(|(code, english, _)| {
+                    *code == self.code && self.message.starts_with(english)
+                });
+                if let Some((_, english, german)) = translation {
+                    self.message = format!("{}{}", german, &self.message[english.len()..]);
+                }
+            }
+            self
+        }
+    }
+
+    impl From<&Rejection> for ApiError {
+        fn from(err: &Rejection) -> Self {
+            if err.find::<NotFound>().is_some() {
+                Self::new(StatusCode::NOT_FOUND, "not_found", "user not found")
+            } else if err.is_not_found() {
+                Self::new(StatusCode::NOT_FOUND, "not_found", "route not found")
+            } else if err.find::<Conflict>().is_some() {
+                Self::new(StatusCode::CONFLICT, "conflict", "user id already exists")
+            } else if err.find::<StorageFailure>().is_some() {
+                Self::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "storage",
+                    "internal storage error",
+                )
+            } else if let Some(Invalid(errors)) = err.find() {
+                Self {
+                    details: errors.clone(),
+                    ..Self::new(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "validation",
+                        "validation failed",
+                    )
+                }
+            } else if err.find::<VersionConflict>().is_some() {
+                Self::new(
+                    StatusCode::CONFLICT,
+                    "version_conflict",
+                    "user was modified in the meantime",
+                )
+            } else if err.find::<PreconditionRequired>().is_some() {
+                Self::new(
+                    StatusCode::PRECONDITION_REQUIRED,
+                    "precondition_required",
+                    "missing If-Match header with the user version",
+                )
+            } else if err.find::<IdMismatch>().is_some() {
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "id_mismatch",
+                    "id in path and body differ",
+                )
+            } else if let Some(InvalidBody(reason)) = err.find() {
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "bad_json",
+                    format!("invalid request body: {}", reason),
+                )
+            } else if let Some(InvalidPatch(reason)) = err.find() {
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_patch",
+                    format!("patch can't be applied: {}", reason),
+                )
+            } else if let Some(InvalidQuery(reason)) = err.find() {
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_query",
+                    format!("invalid query: {}", reason),
+                )
+            } else if err.find::<MissingTenant>().is_some() {
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "missing_tenant",
+                    "missing X-Tenant-Id header",
+                )
+            } else if err.find::<Unauthorized>().is_some() {
+                Self::new(
+                    StatusCode::UNAUTHORIZED,
+                    "unauthorized",
+                    "missing bearer token",
+                )
+            } else if err.find::<CorsForbidden>().is_some() {
+                Self::new(
+                    StatusCode::FORBIDDEN,
+                    "cors_forbidden",
+                    "cross-origin request not allowed",
+                )
+            } else if err.find::<Forbidden>().is_some() {
+                Self::new(StatusCode::FORBIDDEN, "forbidden", "invalid bearer token")
+            } else if err.find::<IdempotencyKeyReused>().is_some() {
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "idempotency_key_reused",
+                    "idempotency key was used for a different request",
+                )
+            } else if err.find::<IdempotencyKeyInUse>().is_some() {
+                Self::new(
+                    StatusCode::CONFLICT,
+                    "idempotency_key_in_use",
+                    "a request with this idempotency key is in progress",
+                )
+            } else if let Some(InvalidDirective(reason)) = err.find() {
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_directive",
+                    format!("invalid log directive: {}", reason),
+                )
+            } else if err.find::<Panicked>().is_some() {
+                Self::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal",
+                    "internal server error",
+                )
+            } else if err.find::<Timeout>().is_some() {
+                Self::new(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "timeout",
+                    "request took too long",
+                )
+            } else if let Some(TooManyRequests { retry_after }) = err.find() {
+                Self {
+                    retry_after: Some(*retry_after),
+                    ..Self::new(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "rate_limited",
+                        "too many requests",
+                    )
+                }
+            } else if err.find::<Overloaded>().is_some() {
+                Self {
+                    retry_after: Some(1),
+                    ..Self::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "overloaded",
+                        "too many concurrent requests",
+                    )
+                }
+            } else if err.find::<PayloadTooLarge>().is_some() {
+                Self::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "too_large",
+                    "payload too large",
+                )
+            } else if let Some(err) = err.find::<BodyDeserializeError>() {
+                // the serde error names the offending field and expected type, e.g.
+                // "missing field `lastName` at line 1 column 2"
+                let reason = err
+                    .source()
+                    .map_or_else(|| err.to_string(), ToString::to_string);
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "bad_json",
+                    format!("invalid request body: {}", reason),
+                )
+            } else if err.find::<UnsupportedMediaType>().is_some() {
+                Self::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported_media_type",
+                    "unsupported media type",
+                )
+            } else if err.find::<LengthRequired>().is_some() {
+                Self::new(
+                    StatusCode::LENGTH_REQUIRED,
+                    "length_required",
+                    "content-length header required",
+                )
+            } else if let Some(UnsupportedMethod { allow }) = err.find() {
+                Self {
+                    allow: Some(*allow),
+                    ..Self::new(
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "method_not_allowed",
+                        "method not allowed",
+                    )
+                }
+            } else if err.find::<MethodNotAllowed>().is_some() {
+                Self::new(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "method_not_allowed",
+                    "method not allowed",
+                )
+            } else if err.find::<MissingHeader>().is_some() || err.find::<InvalidHeader>().is_some()
+            {
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "bad_request",
+                    "invalid request headers",
+                )
+            } else {
+                tracing::error!(?err, "unhandled rejection");
+                Self::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal",
+                    "internal server error",
+                )
+            }
+        }
+    }
+
+    impl warp::Reply for ApiError {
+        fn into_response(self) -> Response {
+            #[derive(Serialize)]
+            struct Body<'a> {
+                error: &'a ApiError,
+            }
+
+            let body = warp::reply::json(&Body { error: &self });
+            let mut response = warp::reply::with_status(body, self.status).into_response();
+            if self.status == StatusCode::UNAUTHORIZED {
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            if let Some(retry_after) = self.retry_after {
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, retry_after.into());
+            }
+            if let Some(allow) = self.allow {
+                response
+                    .headers_mut()
+                    .insert(ALLOW, HeaderValue::from_static(allow));
+            }
+            response
+        }
+    }
+
+    pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Infallible> {
+        Ok(localized_rejection(&err, None))
+    }
+
+    /// Replies with the error for the rejection, in the most preferred supported language.
+    pub fn localized_rejection(err: &Rejection, accept_language: Option<&str>) -> Response {
+        let error = ApiError::from(err).localize(Language::negotiate(accept_language));
+        // the codes are a fixed set, which keeps the label cardinality bounded
+        record_error(error.code);
+        error.into_response()
+    }
+}
+
+mod models {
+    use super::observability::record_lock_wait;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use dashmap::mapref::entry::Entry;
+    use dashmap::DashMap;
+    use serde::{Deserialize, Deserializer, Serialize};
+    #[cfg(feature = "sqlite")]
+    use sqlx::sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteRow,
+    };
+    #[cfg(feature = "sqlite")]
+    use sqlx::{Row, SqliteExecutor};
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    #[cfg(feature = "sqlite")]
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    #[cfg(feature = "sqlite")]
+    use std::sync::Mutex;
+    #[cfg(feature = "sqlite")]
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+    pub type State = Arc<RwLock<HashMap<u64, User>>>;
+
+    pub fn init_state() -> State {
+        Arc::new(RwLock::new(HashMap::new()))
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum RepositoryError {
+        NotFound,
+        Conflict,
+        /// The user was updated since the version the update is based on.
+        Stale,
+        // only the SQLite repository can fail to reach its storage
+        #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+        Storage(String),
+    }
+
+    #[cfg(feature = "sqlite")]
+    impl From<sqlx::Error> for RepositoryError {
+        fn from(err: sqlx::Error) -> Self {
+            match err {
+                sqlx::Error::RowNotFound => Self::NotFound,
+                sqlx::Error::Database(ref db) if db.is_unique_violation() => Self::Conflict,
+                err => Self::Storage(err.to_string()),
+            }
+        }
+    }
+
+    const GENDER_LABELS: [&str; 4] = ["female", "male", "unspecified", "other"];
+
+    /// Active users per gender, self-described genders are all counted as `other`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct ActiveUsers([i64; 4]);
+
+    impl ActiveUsers {
+        pub fn count<'a>(users: impl IntoIterator<Item = &'a User>) -> Self {
+            let mut active = Self::default();
+            for user in users {
+                active.add(user, 1);
+            }
+            active
+        }
+
+        /// Adds `n` to the gender of the user, if it's not deleted.
+        fn add(&mut self, user: &User, n: i64) {
+            if user.deleted_at.is_none() {
+                let index = match user.gender {
+                    Gender::Female => 0,
+                    Gender::Male => 1,
+                    Gender::Unspecified => 2,
+                    Gender::Other(_) => 3,
+                };
+                self.0[index] += n;
+            }
+        }
+
+        pub fn total(&self) -> i64 {
+            self.0.iter().sum()
+        }
+
+        /// Every gender label, including the ones without users.
+        pub fn by_gender(&self) -> impl Iterator<Item = (&'static str, i64)> + '_ {
+            GENDER_LABELS.iter().copied().zip(self.0.iter().copied())
+        }
+    }
+
+    #[async_trait]
+    pub trait Repository: Clone + Send + Sync + 'static {
+        /// Counts the active users without waiting for the store, so it can be called while
+        /// collecting metrics. `None` if the store is busy.
+        fn count_active(&self) -> Option<ActiveUsers>;
+        /// Lists all users, including the deleted ones.
+        async fn list(&self) -> Result<Vec<User>, RepositoryError>;
+        async fn get(&self, id: u64) -> Result<User, RepositoryError>;
+        async fn create(&self, new_user: NewUser) -> Result<User, RepositoryError>;
+        /// Inserts all users or none of them.
+        async fn create_many(&self, new_users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError>;
+        /// Replaces all users, including the deleted ones, keeping the ids of the given users.
+        async fn replace_all(&self, users: Vec<User>) -> Result<Vec<User>, RepositoryError>;
+        /// Stores the user if `user.version` is still current, and increments the version.
+        async fn update(&self, user: User) -> Result<User, RepositoryError>;
+        /// Marks the user as deleted, it's not found by `get`, `update` and `delete` anymore.
+        async fn delete(&self, id: u64) -> Result<(), RepositoryError>;
+        async fn ping(&self) -> Result<(), RepositoryError>;
+    }
+
+    #[derive(Clone)]
+    pub struct InMemoryRepo {
+        state: State,
+        next_id: Arc<AtomicU64>,
+    }
+
+    impl InMemoryRepo {
+        pub fn new(state: State) -> Self {
+            Self {
+                state,
+                next_id: Arc::new(AtomicU64::new(1)),
+            }
+        }
+
+        async fn read(&self) -> RwLockReadGuard<'_, HashMap<u64, User>> {
+            let started = Instant::now();
+            let users = self.state.read().await;
+            record_lock_wait("read", started.elapsed());
+            users
+        }
+
+        async fn write(&self) -> RwLockWriteGuard<'_, HashMap<u64, User>> {
+            let started = Instant::now();
+            let users = self.state.write().await;
+            record_lock_wait("write", started.elapsed());
+            users
+        }
+    }
+
+    // ids handed out after a replace must not collide with the replaced users
+    fn next_id(users: &[User]) -> u64 {
+        users.iter().map(|user| user.id + 1).max().unwrap_or(1)
+    }
+
+    #[async_trait]
+    impl Repository for InMemoryRepo {
+        fn count_active(&self) -> Option<ActiveUsers> {
+            let users = self.state.try_read().ok()?;
+            Some(ActiveUsers::count(users.values()))
+        }
+
+        async fn list(&self) -> Result<Vec<User>, RepositoryError> {
+            let mut users: Vec<User> = self.read().await.values().cloned().collect();
+            users.sort_by_key(|user| user.id);
+            Ok(users)
+        }
+
+        async fn get(&self, id: u64) -> Result<User, RepositoryError> {
+            let users = self.read().await;
+            users
+                .get(&id)
+                .filter(|user| user.deleted_at.is_none())
+                .cloned()
+                .ok_or(RepositoryError::NotFound)
+        }
+
+        async fn create(&self, new_user: NewUser) -> Result<User, RepositoryError> {
+            let user = new_user.into_user(self.next_id.fetch_add(1, Ordering::Relaxed));
+            let mut users = self.write().await;
+            if users.contains_key(&user.id) {
+                return Err(RepositoryError::Conflict);
+            }
+            users.insert(user.id, user.clone());
+            Ok(user)
+        }
+
+        async fn create_many(&self, new_users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+            let mut users = self.write().await;
+            let created: Vec<User> = new_users
+                .into_iter()
+                .map(|new_user| new_user.into_user(self.next_id.fetch_add(1, Ordering::Relaxed)))
+                .collect();
+            if created.iter().any(|user| users.contains_key(&user.id)) {
+                return Err(RepositoryError::Conflict);
+            }
+            users.extend(created.iter().map(|user| (user.id, user.clone())));
+            Ok(created)
+        }
+
+        async fn replace_all(&self, replacement: Vec<User>) -> Result<Vec<User>, RepositoryError> {
+            let mut users = self.write().await;
+            *users = replacement
+                .iter()
+                .map(|user| (user.id, user.clone()))
+                .collect();
+            self.next_id
+                .fetch_max(next_id(&replacement), Ordering::Relaxed);
+            let mut replaced: Vec<User> = users.values().cloned().collect();
+            replaced.sort_by_key(|user| user.id);
+            Ok(replaced)
+        }
+
+        async fn update(&self, mut user: User) -> Result<User, RepositoryError> {
+            let mut users = self.write().await;
+            let existing = users
+                .get_mut(&user.id)
+                .filter(|existing| existing.deleted_at.is_none())
+                .ok_or(RepositoryError::NotFound)?;
+            if existing.version != user.version {
+                return Err(RepositoryError::Stale);
+            }
+            user.created_at = existing.created_at;
+            user.version += 1;
+            *existing = user.clone();
+            Ok(user)
+        }
+
+        async fn delete(&self, id: u64) -> Result<(), RepositoryError> {
+            let mut users = self.write().await;
+            let user = users
+                .get_mut(&id)
+                .filter(|user| user.deleted_at.is_none())
+                .ok_or(RepositoryError::NotFound)?;
+            user.deleted_at = Some(Utc::now());
+            Ok(())
+        }
+
+        async fn ping(&self) -> Result<(), RepositoryError> {
+            Ok(())
+        }
+    }
+
+    /// Keeps the users in a sharded map, so requests for different users don't contend.
+    #[derive(Clone)]
+    pub struct DashMapRepo {
+        users: Arc<DashMap<u64, User>>,
+        next_id: Arc<AtomicU64>,
+    }
+
+    impl DashMapRepo {
+        pub fn new() -> Self {
+            Self {
+                users: Arc::new(DashMap::new()),
+                next_id: Arc::new(AtomicU64::new(1)),
+            }
+        }
+
+        fn insert(&self, user: User) -> Result<(), RepositoryError> {
+            match self.users.entry(user.id) {
+                Entry::Occupied(_) => Err(RepositoryError::Conflict),
+                Entry::Vacant(entry) => {
+                    entry.insert(user);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Repository for DashMapRepo {
+        fn count_active(&self) -> Option<ActiveUsers> {
+            let mut active = ActiveUsers::default();
+            for user in self.users.iter() {
+                active.add(&user, 1);
+            }
+            Some(active)
+        }
+
+        async fn list(&self) -> Result<Vec<User>, RepositoryError> {
+            let mut users: Vec<User> = self.users.iter().map(|user| user.clone()).collect();
+            users.sort_by_key(|user| user.id);
+            Ok(users)
+        }
+
+        async fn get(&self, id: u64) -> Result<User, RepositoryError> {
+            self.users
+                .get(&id)
+                .filter(|user| user.deleted_at.is_none())
+                .map(|user| user.clone())
+                .ok_or(RepositoryError::NotFound)
+        }
+
+        async fn create(&self, new_user: NewUser) -> Result<User, RepositoryError> {
+            let user = new_user.into_user(self.next_id.fetch_add(1, Ordering::Relaxed));
+            self.insert(user.clone())?;
+            Ok(user)
+        }
+
+        async fn create_many(&self, new_users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+            let created: Vec<User> = new_users
+                .into_iter()
+                .map(|new_user| new_user.into_user(self.next_id.fetch_add(1, Ordering::Relaxed)))
+                .collect();
+            for (inserted, user) in created.iter().enumerate() {
+                if let Err(err) = self.insert(user.clone()) {
+                    // there is no lock across the shards, so the inserted users are rolled back
+                    for user in &created[..inserted] {
+                        self.users.remove(&user.id);
+                    }
+                    return Err(err);
+                }
+            }
+            Ok(created)
+        }
+
+        // readers may briefly see a partially replaced collection, as there's no lock across shards
+        async fn replace_all(&self, replacement: Vec<User>) -> Result<Vec<User>, RepositoryError> {
+            self.users.clear();
+            for user in &replacement {
+                self.users.insert(user.id, user.clone());
+            }
+            self.next_id
+                .fetch_max(next_id(&replacement), Ordering::Relaxed);
+            self.list().await
+        }
+
+        async fn update(&self, mut user: User) -> Result<User, RepositoryError> {
+            let mut existing = match self.users.get_mut(&user.id) {
+                Some(existing) if existing.deleted_at.is_none() => existing,
+                _ => return Err(RepositoryError::NotFound),
+            };
+            if existing.version != user.version {
+                return Err(RepositoryError::Stale);
+            }
+            user.created_at = existing.created_at;
+            user.version += 1;
+            *existing = user.clone();
+            Ok(user)
+        }
+
+        async fn delete(&self, id: u64) -> Result<(), RepositoryError> {
+            match self.users.get_mut(&id) {
+                Some(mut user) if user.deleted_at.is_none() => {
+                    user.deleted_at = Some(Utc::now());
+                    Ok(())
+                }
+                _ => Err(RepositoryError::NotFound),
+            }
+        }
+
+        async fn ping(&self) -> Result<(), RepositoryError> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[derive(Clone)]
+    pub struct SqliteRepo {
+        pool: SqlitePool,
+        /// Kept along with every change, as counting needs a query.
+        active: Arc<Mutex<ActiveUsers>>,
+    }
+
+    #[cfg(feature = "sqlite")]
+    impl SqliteRepo {
+        pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+            let options = SqliteConnectOptions::from_str(url)?
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal)
+                .busy_timeout(Duration::from_secs(5));
+            // every connection to an in-memory database would see its own, empty database
+            let max_connections = if options.get_filename() == Path::new(":memory:") {
+                1
+            } else {
+                5
+            };
+            let pool = SqlitePoolOptions::new()
+                .max_connections(max_connections)
+                .connect_with(options)
+                .await?;
+            sqlx::migrate!().run(&pool).await?;
+            let repo = Self {
+                pool,
+                active: Arc::default(),
+            };
+            repo.recount().await?;
+            Ok(repo)
+        }
+
+        async fn recount(&self) -> Result<(), sqlx::Error> {
+            let rows = sqlx::query("SELECT * FROM users WHERE deleted_at IS NULL")
+                .fetch_all(&self.pool)
+                .await?;
+            let users = rows
+                .into_iter()
+                .map(user_from_row)
+                .collect::<Result<Vec<_>, _>>()?;
+            *self.active.lock().unwrap() = ActiveUsers::count(&users);
+            Ok(())
+        }
+
+        fn count(&self, user: &User, n: i64) {
+            self.active.lock().unwrap().add(user, n);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn user_from_row(row: SqliteRow) -> Result<User, sqlx::Error> {
+        let gender: String = row.try_get("gender")?;
+        Ok(User {
+            id: row.try_get::<i64, _>("id")? as u64,
+            first_name: row.try_get("first_name")?,
+            email: row.try_get("email")?,
+            last_name: row.try_get("last_name")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+            version: row.try_get::<i64, _>("version")? as u64,
+            deleted_at: row.try_get("deleted_at")?,
+            gender: gender
+                .parse()
+                .map_err(|err: String| sqlx::Error::ColumnDecode {
+                    index: "gender".into(),
+                    source: err.into(),
+                })?,
+        })
+    }
+
+    #[cfg(feature = "sqlite")]
+    async fn insert_user<'e>(
+        executor: impl SqliteExecutor<'e>,
+        new_user: &NewUser,
+    ) -> Result<User, sqlx::Error> {
+        let now = Utc::now();
+        let row = sqlx::query(
+            "INSERT INTO users (first_name, last_name, gender, email, created_at, updated_at) \
+             VALUES (?, ?, ?, ?, ?, ?) RETURNING *",
+        )
+        .bind(&new_user.first_name)
+        .bind(&new_user.last_name)
+        .bind(new_user.gender.as_str())
+        .bind(&new_user.email)
+        .bind(now)
+        .bind(now)
+        .fetch_one(executor)
+        .await?;
+        user_from_row(row)
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_trait]
+    impl Repository for SqliteRepo {
+        fn count_active(&self) -> Option<ActiveUsers> {
+            Some(*self.active.lock().unwrap())
+        }
+
+        async fn list(&self) -> Result<Vec<User>, RepositoryError> {
+            let rows = sqlx::query("SELECT * FROM users ORDER BY id")
+                .fetch_all(&self.pool)
+                .await?;
+            let users = rows
+                .into_iter()
+                .map(user_from_row)
+                .collect::<Result<_, _>>()?;
+            Ok(users)
+        }
+
+        async fn get(&self, id: u64) -> Result<User, RepositoryError> {
+            let row = sqlx::query("SELECT * FROM users WHERE id = ? AND deleted_at IS NULL")
+                .bind(id as i64)
+                .fetch_one(&self.pool)
+                .await?;
+            Ok(user_from_row(row)?)
+        }
+
+        async fn create(&self, new_user: NewUser) -> Result<User, RepositoryError> {
+            let user = insert_user(&self.pool, &new_user).await?;
+            self.count(&user, 1);
+            Ok(user)
+        }
+
+        async fn create_many(&self, new_users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+            let mut tx = self.pool.begin().await?;
+            let mut users = Vec::with_capacity(new_users.len());
+            for new_user in &new_users {
+                users.push(insert_user(&mut *tx, new_user).await?);
+            }
+            tx.commit().await?;
+            for user in &users {
+                self.count(user, 1);
+            }
+            Ok(users)
+        }
+
+        async fn replace_all(&self, replacement: Vec<User>) -> Result<Vec<User>, RepositoryError> {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM users").execute(&mut *tx).await?;
+            for user in &replacement {
+                sqlx::query(
+                    "INSERT INTO users (id, first_name, last_name, gender, email, created_at, \
+                     updated_at, version, deleted_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                )
+                .bind(user.id as i64)
+                .bind(&user.first_name)
+                .bind(&user.last_name)
+                .bind(user.gender.as_str())
+                .bind(&user.email)
+                .bind(user.created_at)
+                .bind(user.updated_at)
+                .bind(user.version as i64)
+                .bind(user.deleted_at)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            let users = self.list().await?;
+            *self.active.lock().unwrap() = ActiveUsers::count(&users);
+            Ok(users)
+        }
+
+        async fn update(&self, user: User) -> Result<User, RepositoryError> {
+            // a version is only stored once, so this is the user the update replaces
+            let previous = sqlx::query("SELECT * FROM users WHERE id = ? AND version = ?")
+                .bind(user.id as i64)
+                .bind(user.version as i64)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(user_from_row)
+                .transpose()?;
+            let row = sqlx::query(
+                "UPDATE users SET first_name = ?, last_name = ?, gender = ?, email = ?, \
+                 updated_at = ?, version = version + 1 \
+                 WHERE id = ? AND version = ? AND deleted_at IS NULL RETURNING *",
+            )
+            .bind(&user.first_name)
+            .bind(&user.last_name)
+            .bind(user.gender.as_str())
+            .bind(&user.email)
+            .bind(user.updated_at)
+            .bind(user.id as i64)
+            .bind(user.version as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+            match (row, previous) {
+                (Some(row), Some(previous)) => {
+                    let updated = user_from_row(row)?;
+                    self.count(&previous, -1);
+                    self.count(&updated, 1);
+                    Ok(updated)
+                }
+                // another update stored the version between both queries
+                (Some(row), None) => {
+                    self.recount().await?;
+                    Ok(user_from_row(row)?)
+                }
+                // either there is no such user, or it has another version
+                (None, _) => {
+                    self.get(user.id).await?;
+                    Err(RepositoryError::Stale)
+                }
+            }
+        }
+
+        async fn delete(&self, id: u64) -> Result<(), RepositoryError> {
+            let row = sqlx::query(
+                "UPDATE users SET deleted_at = ? WHERE id = ? AND deleted_at IS NULL RETURNING *",
+            )
+            .bind(Utc::now())
+            .bind(id as i64)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(RepositoryError::NotFound)?;
+            let deleted = User {
+                deleted_at: None,
+                ..user_from_row(row)?
+            };
+            self.count(&deleted, -1);
+            Ok(())
+        }
+
+        async fn ping(&self) -> Result<(), RepositoryError> {
+            sqlx::query("SELECT 1").execute(&self.pool).await?;
+            Ok(())
+        }
+    }
+
+    /// Known genders are camelCase strings, any other value is kept as a self-described one.
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    #[serde(try_from = "String", into = "String")]
+    pub enum Gender {
+        Female,
+        Male,
+        Unspecified,
+        Other(String),
+    }
+
+    /// Inputs that omit the gender default to `Unspecified`.
+    impl Default for Gender {
+        fn default() -> Self {
+            Gender::Unspecified
+        }
+    }
+
+    impl Gender {
+        const MAX_OTHER_LEN: usize = 64;
+
+        pub fn as_str(&self) -> &str {
+            match self {
+                Gender::Female => "female",
+                Gender::Male => "male",
+                Gender::Unspecified => "unspecified",
+                Gender::Other(other) => other,
+            }
+        }
+    }
+
+    impl FromStr for Gender {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "female" => Ok(Gender::Female),
+                "male" => Ok(Gender::Male),
+                "unspecified" => Ok(Gender::Unspecified),
+                _ if s.trim().is_empty() => Err("gender must not be blank".into()),
+                _ if s.chars().count() > Self::MAX_OTHER_LEN => Err(format!(
+                    "gender must not exceed {} characters",
+                    Self::MAX_OTHER_LEN
+                )),
+                _ => Ok(Gender::Other(s.into())),
+            }
+        }
+    }
+
+    impl TryFrom<String> for Gender {
+        type Error = String;
+
+        fn try_from(value: String) -> Result<Self, Self::Error> {
+            value.parse()
+        }
+    }
+
+    impl From<Gender> for String {
+        fn from(gender: Gender) -> Self {
+            match gender {
+                Gender::Other(other) => other,
+                known => known.as_str().into(),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct User {
+        pub id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub first_name: Option<String>,
+        pub last_name: String,
+        pub gender: Gender,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub email: Option<String>,
+        // managed by the server, values sent by clients are ignored
+        #[serde(skip_deserializing, default = "Utc::now")]
+        pub created_at: DateTime<Utc>,
+        #[serde(skip_deserializing, default = "Utc::now")]
+        pub updated_at: DateTime<Utc>,
+        /// Incremented on every update, clients pass it in `If-Match` to update the user.
+        #[serde(skip_deserializing)]
+        pub version: u64,
+        #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+        pub deleted_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Debug, Clone, Serialize, PartialEq)]
+    pub struct ValidationError {
+        pub field: &'static str,
+        pub reason: &'static str,
+    }
+
+    fn validate_last_name(last_name: &str, errors: &mut Vec<ValidationError>) {
+        if last_name.trim().is_empty() {
+            errors.push(ValidationError {
+                field: "lastName",
+                reason: "must not be blank",
+            });
+        }
+    }
+
+    fn validate_email(email: Option<&str>, errors: &mut Vec<ValidationError>) {
+        let valid = email.is_none_or(|email| match email.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.is_empty()
+                    && !domain.contains('@')
+                    && !email.contains(char::is_whitespace)
+            }
+            None => false,
+        });
+        if !valid {
+            errors.push(ValidationError {
+                field: "email",
+                reason: "must be a valid email address",
+            });
+        }
+    }
+
+    fn to_result(errors: Vec<ValidationError>) -> Result<(), Vec<ValidationError>> {
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    impl User {
+        pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            let mut errors = Vec::new();
+            validate_last_name(&self.last_name, &mut errors);
+            validate_email(self.email.as_deref(), &mut errors);
+            to_result(errors)
+        }
+
+        pub fn apply(&mut self, patch: UserPatch) {
+            if let Some(first_name) = patch.first_name {
+                self.first_name = first_name;
+            }
+            if let Some(last_name) = patch.last_name {
+                self.last_name = last_name;
+            }
+            if let Some(gender) = patch.gender {
+                self.gender = gender;
+            }
+            if let Some(email) = patch.email {
+                self.email = email;
+            }
+        }
+
+        pub fn touch(&mut self) {
+            self.updated_at = Utc::now();
+        }
+    }
+
+    #[derive(Debug, Deserialize, Clone, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct NewUser {
+        pub first_name: Option<String>,
+        pub last_name: String,
+        #[serde(default)]
+        pub gender: Gender,
+        pub email: Option<String>,
+    }
+
+    impl NewUser {
+        pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            let mut errors = Vec::new();
+            validate_last_name(&self.last_name, &mut errors);
+            validate_email(self.email.as_deref(), &mut errors);
+            to_result(errors)
+        }
+
+        pub fn into_user(self, id: u64) -> User {
+            let mut builder = UserBuilder::new(id, self.last_name).gender(self.gender);
+            if let Some(first_name) = self.first_name {
+                builder = builder.first_name(first_name);
+            }
+            if let Some(email) = self.email {
+                builder = builder.email(email);
+            }
+            builder.build()
+        }
+    }
+
+    /// Builds a fresh user, which is unspecified in gender and has no first name or email.
+    pub struct UserBuilder {
+        user: User,
+    }
+
+    impl UserBuilder {
+        pub fn new(id: u64, last_name: impl Into<String>) -> Self {
+            let now = Utc::now();
+            let user = User {
+                id,
+                first_name: None,
+                last_name: last_name.into(),
+                gender: Gender::Unspecified,
+                email: None,
+                created_at: now,
+                updated_at: now,
+                version: 1,
+                deleted_at: None,
+            };
+            Self { user }
+        }
+
+        pub fn first_name(mut self, first_name: impl Into<String>) -> Self {
+            self.user.first_name = Some(first_name.into());
+            self
+        }
+
+        pub fn gender(mut self, gender: Gender) -> Self {
+            self.user.gender = gender;
+            self
+        }
+
+        pub fn email(mut self, email: impl Into<String>) -> Self {
+            self.user.email = Some(email.into());
+            self
+        }
+
+        pub fn build(self) -> User {
+            self.user
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Pagination {
+        pub limit: Option<usize>,
+        pub offset: Option<usize>,
+    }
+
+    impl Pagination {
+        const DEFAULT_LIMIT: usize = 50;
+        const MAX_LIMIT: usize = 200;
+
+        pub fn limit(&self) -> usize {
+            self.limit
+                .unwrap_or(Self::DEFAULT_LIMIT)
+                .min(Self::MAX_LIMIT)
+        }
+
+        pub fn offset(&self) -> usize {
+            self.offset.unwrap_or(0)
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct UserFilter {
+        pub gender: Option<Gender>,
+        pub q: Option<String>,
+        #[serde(rename = "includeDeleted", default)]
+        pub include_deleted: bool,
+        #[serde(rename = "minId")]
+        pub min_id: Option<u64>,
+        #[serde(rename = "maxId")]
+        pub max_id: Option<u64>,
+    }
+
+    impl UserFilter {
+        /// Rejects an empty id range, rather than silently matching nobody.
+        pub fn validate(&self) -> Result<(), String> {
+            match (self.min_id, self.max_id) {
+                (Some(min_id), Some(max_id)) if min_id > max_id => {
+                    Err(format!("minId {} must not exceed maxId {}", min_id, max_id))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        pub fn matches(&self, user: &User) -> bool {
+            (self.include_deleted || user.deleted_at.is_none())
+                && self.in_id_range(user.id)
+                && self
+                    .gender
+                    .as_ref()
+                    .is_none_or(|gender| *gender == user.gender)
+                && self.q.as_deref().is_none_or(|q| name_contains(user, q))
+        }
+
+        // both bounds are inclusive
+        fn in_id_range(&self, id: u64) -> bool {
+            let min_id = self.min_id.unwrap_or(u64::MIN);
+            let max_id = self.max_id.unwrap_or(u64::MAX);
+            (min_id..=max_id).contains(&id)
+        }
+    }
+
+    /// Matches the first or last name case-insensitively, an empty query matches everyone.
+    pub fn name_contains(user: &User, query: &str) -> bool {
+        let query = query.to_lowercase();
+        let contains = |name: &str| name.to_lowercase().contains(&query);
+        contains(&user.last_name) || user.first_name.as_deref().is_some_and(contains)
+    }
+
+    #[derive(Debug, Deserialize, Clone, Copy)]
+    #[serde(rename_all = "camelCase")]
+    pub enum SortField {
+        Id,
+        LastName,
+        FirstName,
+    }
+
+    #[derive(Debug, Deserialize, Clone, Copy, Default)]
+    #[serde(rename_all = "camelCase")]
+    pub enum SortOrder {
+        #[default]
+        Asc,
+        Desc,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Sorting {
+        pub sort: Option<SortField>,
+        #[serde(default)]
+        pub order: SortOrder,
+    }
+
+    impl Sorting {
+        /// Sorts stably, users without a first name come first in ascending order.
+        pub fn sort(&self, users: &mut [User]) {
+            let field = match self.sort {
+                Some(field) => field,
+                None => return,
+            };
+            users.sort_by(|a, b| {
+                let ordering = match field {
+                    SortField::Id => a.id.cmp(&b.id),
+                    SortField::LastName => a.last_name.cmp(&b.last_name),
+                    SortField::FirstName => a.first_name.cmp(&b.first_name),
+                };
+                match self.order {
+                    SortOrder::Asc => ordering,
+                    SortOrder::Desc => ordering.reverse(),
+                }
+            });
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct FieldSelection {
+        pub fields: Option<Fields>,
+    }
+
+    /// A comma-separated list of user fields, unknown names are rejected rather than
+    /// ignored, so a typo doesn't silently produce users without that field.
+    #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+    #[serde(try_from = "String")]
+    pub struct Fields(Vec<String>);
+
+    impl Fields {
+        // the serialized names of the members of `User`
+        const KNOWN: [&'static str; 9] = [
+            "id",
+            "firstName",
+            "lastName",
+            "gender",
+            "email",
+            "createdAt",
+            "updatedAt",
+            "version",
+            "deletedAt",
+        ];
+
+        /// Serializes the user, keeping only the selected members.
+        pub fn select(&self, user: &User) -> serde_json::Value {
+            let mut value = serde_json::to_value(user).unwrap_or_default();
+            if let Some(members) = value.as_object_mut() {
+                members.retain(|name, _| self.0.contains(name));
+            }
+            value
+        }
+    }
+
+    impl TryFrom<String> for Fields {
+        type Error = String;
+
+        fn try_from(value: String) -> Result<Self, Self::Error> {
+            let mut fields = Vec::new();
+            for name in value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+            {
+                if !Self::KNOWN.contains(&name) {
+                    return Err(format!("unknown field `{}`", name));
+                }
+                fields.push(name.to_owned());
+            }
+            if fields.is_empty() {
+                return Err("fields must not be empty".into());
+            }
+            Ok(Fields(fields))
+        }
+    }
+
+    /// The criteria, order and page of a user query, as sent to `/users/search`.
+    #[derive(Debug, Deserialize)]
+    pub struct SearchRequest {
+        #[serde(flatten)]
+        pub filter: UserFilter,
+        #[serde(flatten)]
+        pub sorting: Sorting,
+        #[serde(flatten)]
+        pub pagination: Pagination,
+    }
+
+    /// A page of matching users, along with the number of all matches.
+    pub struct SearchResult {
+        pub total: usize,
+        pub users: Vec<User>,
+    }
+
+    impl SearchRequest {
+        /// Filters and sorts the users before taking the page, so the total counts all matches.
+        pub fn run(&self, mut users: Vec<User>) -> SearchResult {
+            users.retain(|user| self.filter.matches(user));
+            let total = users.len();
+            self.sorting.sort(&mut users);
+            let users = users
+                .into_iter()
+                .skip(self.pagination.offset())
+                .take(self.pagination.limit())
+                .collect();
+            SearchResult { total, users }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct UserPatch {
+        #[serde(default, deserialize_with = "deserialize_some")]
+        pub first_name: Option<Option<String>>,
+        pub last_name: Option<String>,
+        pub gender: Option<Gender>,
+        #[serde(default, deserialize_with = "deserialize_some")]
+        pub email: Option<Option<String>>,
+    }
+
+    /// Applies a JSON Merge Patch (RFC 7386), where `null` removes a member.
+    pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+        let patch = match patch.as_object() {
+            Some(patch) => patch,
+            None => {
+                *target = patch.clone();
+                return;
+            }
+        };
+        if !target.is_object() {
+            *target = serde_json::Value::Object(serde_json::Map::new());
+        }
+        if let Some(target) = target.as_object_mut() {
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge_patch(target.entry(key).or_insert(serde_json::Value::Null), value);
+                }
+            }
+        }
+    }
+
+    // Distinguishes an explicit `null` (Some(None)) from an absent field (None).
+    fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Deserialize::deserialize(deserializer).map(Some)
+    }
+}
+
+mod observability {
+    use super::config;
+    use super::models::Repository;
+    use lazy_static::lazy_static;
+    use opentelemetry::baggage::BaggageExt;
+    use opentelemetry::metrics::MetricsError;
+    use opentelemetry::metrics::{Counter, UpDownCounter, ValueRecorder};
+    use opentelemetry::propagation::{Extractor, TextMapPropagator};
+    use opentelemetry::sdk;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::trace::Sampler;
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::trace::TraceError;
+    use opentelemetry::trace::{SpanContext, TraceContextExt};
+    use opentelemetry::{global, Context, KeyValue, Unit};
+    use opentelemetry_prometheus::PrometheusExporter;
+    use prometheus::proto::{Metric, MetricFamily, MetricType};
+    use prometheus::Encoder;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+    use std::convert::{TryFrom, TryInto};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::field::display;
+    use tracing::{span, Span, Subscriber};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::{self, Layer};
+    use tracing_subscriber::{reload, EnvFilter, Registry};
+    use warp::http::{HeaderMap, Method};
+    use warp::hyper::body::HttpBody;
+    use warp::log::Info;
+    use warp::reply::Response;
+
+    struct Meters {
+        pub incoming_requests: Counter<u64>,
+        pub duration: ValueRecorder<u64>,
+        pub status_codes: Counter<u64>,
+        pub active_requests: UpDownCounter<i64>,
+        pub request_size: ValueRecorder<u64>,
+        pub response_size: ValueRecorder<u64>,
+        pub errors: Counter<u64>,
+        pub panics: Counter<u64>,
+        pub users_created: Counter<u64>,
+        pub users_total: UpDownCounter<i64>,
+        pub lock_wait: ValueRecorder<f64>,
+    }
+
+    /// Prefixes the metric name with the configured namespace.
+    fn metric_name(name: &str) -> String {
+        if NAMESPACE.is_empty() {
+            name.into()
+        } else {
+            format!("{}_{}", *NAMESPACE, name)
+        }
+    }
+
+    lazy_static! {
+        static ref NAMESPACE: String = config::metrics_namespace();
+        static ref METERS: Meters = {
+            let meter = global::meter("web-service");
+            let incoming_requests = meter.u64_counter(metric_name("incoming_requests")).init();
+            let duration = meter
+                .u64_value_recorder(metric_name("http.server.duration"))
+                .with_unit(Unit::new("milliseconds"))
+                .init();
+            let status_codes = meter.u64_counter(metric_name("status_codes")).init();
+            let active_requests = meter
+                .i64_up_down_counter(metric_name("http.server.active_requests"))
+                .init();
+            let request_size = meter
+                .u64_value_recorder(metric_name("http.server.request.size"))
+                .with_unit(Unit::new("bytes"))
+                .init();
+            let response_size = meter
+                .u64_value_recorder(metric_name("http.server.response.size"))
+                .with_unit(Unit::new("bytes"))
+                .init();
+            let errors = meter.u64_counter(metric_name("errors_total")).init();
+            let panics = meter.u64_counter(metric_name("panics_total")).init();
+            let users_created = meter.u64_counter(metric_name("users_created_total")).init();
+            let users_total = meter.i64_up_down_counter(metric_name("users_total")).init();
+            let lock_wait = meter
+                .f64_value_recorder(metric_name("storage_lock_wait_ms"))
+                .with_unit(Unit::new("milliseconds"))
+                .init();
+            Meters {
+                incoming_requests,
+                duration,
+                status_codes,
+                active_requests,
+                request_size,
+                response_size,
+                errors,
+                panics,
+                users_created,
+                users_total,
+                lock_wait,
+            }
+        };
+    }
+
+    /// Counts the closed spans, which are the ones handed to the tracer for export.
+    #[derive(Clone, Default)]
+    pub struct SpanCounter(Arc<AtomicU64>);
+
+    impl SpanCounter {
+        pub fn count(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for SpanCounter {
+        fn on_close(&self, _id: span::Id, _ctx: layer::Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Swaps the `EnvFilter` of the running subscriber.
+    #[derive(Clone)]
+    pub struct LogFilter(reload::Handle<EnvFilter, Registry>);
+
+    impl LogFilter {
+        pub fn new(handle: reload::Handle<EnvFilter, Registry>) -> Self {
+            Self(handle)
+        }
+
+        pub fn reload(&self, directive: &str) -> Result<(), String> {
+            let filter = EnvFilter::try_new(directive).map_err(|err| err.to_string())?;
+            self.0.reload(filter).map_err(|err| err.to_string())
+        }
+    }
+
+    pub fn resource() -> Resource {
+        let mut attributes = vec![
+            KeyValue::new("service.name", config::service_name()),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            KeyValue::new(
+                "host.name",
+                gethostname::gethostname().to_string_lossy().into_owned(),
+            ),
+        ];
+        if let Ok(environment) = std::env::var("DEPLOYMENT_ENVIRONMENT") {
+            attributes.push(KeyValue::new("deployment.environment", environment));
+        }
+        Resource::new(attributes)
+    }
+
+    pub fn init_metrics_exporter() -> Result<PrometheusExporter, MetricsError> {
+        opentelemetry_prometheus::exporter()
+            .with_resource(resource())
+            .with_default_histogram_boundaries(config::histogram_boundaries())
+            .try_init()
+    }
+
+    #[cfg(not(any(feature = "jaeger", feature = "otlp", feature = "stdout")))]
+    compile_error!(
+        "at least one of the `jaeger`, `otlp` and `stdout` features needs to be enabled"
+    );
+
+    /// The exporters compiled in depend on the `jaeger`, `otlp` and `stdout` features.
+    #[derive(Debug, PartialEq)]
+    pub enum TracesExporter {
+        #[cfg(feature = "jaeger")]
+        Jaeger,
+        #[cfg(feature = "otlp")]
+        Otlp,
+        /// Prints the spans, for local development without a collector.
+        #[cfg(feature = "stdout")]
+        Stdout,
+    }
+
+    impl TracesExporter {
+        const ALL: [&'static str; 3] = ["jaeger", "otlp", "stdout"];
+
+        #[cfg(feature = "jaeger")]
+        const DEFAULT: &'static str = "jaeger";
+        #[cfg(all(feature = "otlp", not(feature = "jaeger")))]
+        const DEFAULT: &'static str = "otlp";
+        #[cfg(all(feature = "stdout", not(any(feature = "jaeger", feature = "otlp"))))]
+        const DEFAULT: &'static str = "stdout";
+
+        pub fn from_env() -> Result<Self, TraceError> {
+            Self::parse(std::env::var("OTEL_TRACES_EXPORTER").ok().as_deref())
+        }
+
+        pub fn parse(value: Option<&str>) -> Result<Self, TraceError> {
+            match value.unwrap_or(Self::DEFAULT) {
+                #[cfg(feature = "jaeger")]
+                "jaeger" => Ok(Self::Jaeger),
+                #[cfg(feature = "otlp")]
+                "otlp" => Ok(Self::Otlp),
+                #[cfg(feature = "stdout")]
+                "stdout" => Ok(Self::Stdout),
+                other if Self::ALL.contains(&other) => Err(format!(
+                    "OTEL_TRACES_EXPORTER {} needs a build with the {} feature",
+                    other, other
+                )
+                .into()),
+                other => Err(format!("unsupported OTEL_TRACES_EXPORTER: {}", other).into()),
+            }
+        }
+    }
+
+    /// Samples the given fraction of root spans, children follow the decision of their parent.
+    pub fn sampler(ratio: f64) -> Sampler {
+        Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)))
+    }
+
+    pub fn init_tracer(
+        service_name: &str,
+        sampler_ratio: f64,
+    ) -> Result<sdk::trace::Tracer, TraceError> {
+        install_tracer(TracesExporter::from_env()?, service_name, sampler_ratio)
+    }
+
+    // OTLP and stdout take the service name from the resource
+    #[cfg_attr(not(feature = "jaeger"), allow(unused_variables))]
+    pub fn install_tracer(
+        exporter: TracesExporter,
+        service_name: &str,
+        sampler_ratio: f64,
+    ) -> Result<sdk::trace::Tracer, TraceError> {
+        let trace_config = sdk::trace::config()
+            .with_resource(resource())
+            .with_sampler(sampler(sampler_ratio));
+        match exporter {
+            #[cfg(feature = "jaeger")]
+            TracesExporter::Jaeger => opentelemetry_jaeger::new_pipeline()
+                .with_service_name(service_name)
+                .with_trace_config(trace_config)
+                .install_batch(opentelemetry::runtime::Tokio),
+            // the endpoint is taken from OTEL_EXPORTER_OTLP_ENDPOINT
+            #[cfg(feature = "otlp")]
+            TracesExporter::Otlp => opentelemetry_otlp::new_pipeline()
+                .with_env()
+                .with_trace_config(trace_config)
+                .with_tonic()
+                .install_batch(opentelemetry::runtime::Tokio),
+            // spans are printed as they end, there's nothing to batch up locally
+            #[cfg(feature = "stdout")]
+            TracesExporter::Stdout => Ok(sdk::export::trace::stdout::new_pipeline()
+                .with_trace_config(trace_config)
+                .install_simple()),
+        }
+    }
+
+    pub struct ServiceMetrics {
+        pub duration_ms: u64,
+        pub status_family: &'static str,
+        pub status_code: u16,
+        pub method: &'static str,
+        pub path: &'static str,
+    }
+
+    impl ServiceMetrics {
+        fn duration_labels(&self) -> Vec<KeyValue> {
+            [
+                KeyValue::new("http.status_code", self.status_family),
+                KeyValue::new("http.method", self.method),
+                KeyValue::new("http.target", self.path),
+            ]
+            .into()
+        }
+
+        fn status_code_labels(&self) -> Vec<KeyValue> {
+            [
+                KeyValue::new("http.status_code", self.status_family),
+                KeyValue::new("http.status_code.exact", i64::from(self.status_code)),
+                KeyValue::new("http.method", self.method),
+            ]
+            .into()
+        }
+
+        fn record(&self) {
+            METERS.incoming_requests.add(1, &[]);
+            // no exemplar with the trace id is attached: the ValueRecorder only takes a value and
+            // labels, and neither opentelemetry-prometheus nor the prometheus encoder, in this or
+            // later versions, can expose exemplars
+            METERS
+                .duration
+                .record(self.duration_ms, &self.duration_labels());
+            METERS.status_codes.add(1, &self.status_code_labels());
+        }
+    }
+
+    impl TryFrom<&Info<'_>> for ServiceMetrics {
+        type Error = &'static str;
+
+        fn try_from(info: &Info) -> Result<Self, Self::Error> {
+            let duration_ms = info.elapsed().as_millis() as u64;
+            let status_code = info.status().as_u16();
+            let status_family = match status_code {
+                500..=599 => Ok("500"),
+                400..=499 => Ok("400"),
+                300..=399 => Ok("300"),
+                200..=299 => Ok("200"),
+                100..=199 => Ok("100"),
+                _ => Err("unknown status code"),
+            }?;
+            let method = method_label(info.method());
+            let path = route_template(info.path());
+            let metrics = Self {
+                duration_ms,
+                status_family,
+                status_code,
+                method,
+                path,
+            };
+
+            Ok(metrics)
+        }
+    }
+
+    /// Methods outside of the standard ones share a label value, which keeps it bounded.
+    pub fn method_label(method: &Method) -> &'static str {
+        match *method {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
+            Method::DELETE => "DELETE",
+            Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+            Method::CONNECT => "CONNECT",
+            Method::TRACE => "TRACE",
+            _ => "_OTHER",
+        }
+    }
+
+    /// The templates of the routes in `filters`, where `{id}` stands for a user id.
+    const ROUTES: &[&str] = &[
+        "/users",
+        "/users/batch",
+        "/users/count",
+        "/users/events",
+        "/users/search",
+        "/users/{id}",
+        "/log-level",
+        "/metrics",
+        "/health",
+        "/ready",
+        "/version",
+    ];
+
+    /// Maps a request path to the template of the route serving it. Ids are folded into the
+    /// template and unknown paths end up as "invalid", so the label values stay bounded.
+    /// Like the routes, segments are case-sensitive and empty ones, as from a trailing
+    /// slash, are ignored.
+    pub fn route_template(path: &str) -> &'static str {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        ROUTES
+            .iter()
+            .copied()
+            .find(|route| {
+                let pattern: Vec<&str> = route.split('/').skip(1).collect();
+                pattern.len() == segments.len()
+                    && pattern
+                        .iter()
+                        .zip(&segments)
+                        .all(|(pattern, segment)| match *pattern {
+                            "{id}" => segment.parse::<u64>().is_ok(),
+                            literal => literal == *segment,
+                        })
+            })
+            .unwrap_or("invalid")
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+
+    pub fn extract_context(headers: &HeaderMap) -> Context {
+        TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+    }
+
+    pub const TENANT_BAGGAGE: &str = "tenant.id";
+
+    /// Adds the tenant to the baggage, which is passed on to downstream services.
+    pub fn with_tenant(cx: Context, tenant: &str) -> Context {
+        cx.with_baggage(vec![KeyValue::new(TENANT_BAGGAGE, tenant.to_owned())])
+    }
+
+    fn span_context(span: &Span) -> Option<SpanContext> {
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+        Some(span_context).filter(SpanContext::is_valid)
+    }
+
+    pub fn current_trace_id() -> Option<String> {
+        span_context(&Span::current()).map(|span_context| span_context.trace_id().to_hex())
+    }
+
+    /// Records the OpenTelemetry ids on a span declaring `trace_id` and `span_id` fields, so they
+    /// show up in the bunyan log records of the span and its children.
+    pub fn record_trace_context(span: &Span) {
+        if let Some(span_context) = span_context(span) {
+            span.record("trace_id", display(span_context.trace_id().to_hex()));
+            span.record("span_id", display(span_context.span_id().to_hex()));
+        }
+    }
+
+    pub trait MetricsExporter: Clone + Send + Sync + 'static {
+        fn export(&self) -> Vec<u8>;
+
+        fn content_type(&self) -> &'static str;
+
+        /// Exports in the OpenMetrics text format, if the exporter supports it.
+        fn export_openmetrics(&self) -> Option<Vec<u8>> {
+            None
+        }
+    }
+
+    impl MetricsExporter for PrometheusExporter {
+        fn export(&self) -> Vec<u8> {
+            encode_text(&self.registry().gather())
+        }
+
+        fn content_type(&self) -> &'static str {
+            "text/plain; version=0.0.4; charset=utf-8"
+        }
+
+        fn export_openmetrics(&self) -> Option<Vec<u8>> {
+            let metric_families = self.registry().gather();
+            Some(encode_openmetrics(&metric_families).into_bytes())
+        }
+    }
+
+    /// Encodes the metric families one by one, so a family which fails to encode is logged and
+    /// left out instead of failing the whole export.
+    pub fn encode_text(metric_families: &[MetricFamily]) -> Vec<u8> {
+        let encoder = prometheus::TextEncoder::new();
+        let mut buf = Vec::new();
+        for family in metric_families {
+            if let Err(err) = encoder.encode(std::slice::from_ref(family), &mut buf) {
+                tracing::error!(%err, family = family.get_name(), "failed to encode metrics");
+            }
+        }
+        buf
+    }
+
+    /// Exports the metrics gathered from a registry as a JSON array with one entry per series.
+    #[derive(Clone)]
+    pub struct JsonExporter {
+        registry: prometheus::Registry,
+    }
+
+    impl JsonExporter {
+        pub fn new(registry: prometheus::Registry) -> Self {
+            Self { registry }
+        }
+    }
+
+    #[derive(Serialize)]
+    struct JsonSeries<'a> {
+        name: &'a str,
+        #[serde(rename = "type")]
+        kind: &'static str,
+        labels: BTreeMap<&'a str, &'a str>,
+        // the sum of the observations for histograms and summaries
+        value: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        count: Option<u64>,
+    }
+
+    impl<'a> JsonSeries<'a> {
+        fn new(family: &'a MetricFamily, metric: &'a Metric) -> Self {
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect();
+            let (kind, value, count) = match family.get_field_type() {
+                MetricType::COUNTER => ("counter", metric.get_counter().get_value(), None),
+                MetricType::GAUGE => ("gauge", metric.get_gauge().get_value(), None),
+                MetricType::UNTYPED => ("unknown", metric.get_untyped().get_value(), None),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let count = Some(histogram.get_sample_count());
+                    ("histogram", histogram.get_sample_sum(), count)
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    let count = Some(summary.get_sample_count());
+                    ("summary", summary.get_sample_sum(), count)
+                }
+            };
+            Self {
+                name: family.get_name(),
+                kind,
+                labels,
+                value,
+                count,
+            }
+        }
+    }
+
+    impl MetricsExporter for JsonExporter {
+        fn export(&self) -> Vec<u8> {
+            let metric_families = self.registry.gather();
+            let series: Vec<JsonSeries> = metric_families
+                .iter()
+                .flat_map(|family| {
+                    let metrics = family.get_metric().iter();
+                    metrics.map(move |metric| JsonSeries::new(family, metric))
+                })
+                .collect();
+            serde_json::to_vec(&series).unwrap_or_else(|err| {
+                tracing::error!(%err, "failed to encode metrics");
+                Vec::new()
+            })
+        }
+
+        fn content_type(&self) -> &'static str {
+            "application/json"
+        }
+    }
+
+    // the prometheus crate only ships an encoder for the 0.0.4 text format
+    fn encode_openmetrics(metric_families: &[MetricFamily]) -> String {
+        let mut out = String::new();
+        for family in metric_families {
+            let name = family.get_name();
+            let (kind, name) = match family.get_field_type() {
+                MetricType::COUNTER => ("counter", name.strip_suffix("_total").unwrap_or(name)),
+                MetricType::GAUGE => ("gauge", name),
+                MetricType::HISTOGRAM => ("histogram", name),
+                MetricType::SUMMARY => ("summary", name),
+                MetricType::UNTYPED => ("unknown", name),
+            };
+            if !family.get_help().is_empty() {
+                let help = escape_openmetrics(family.get_help());
+                out.push_str(&format!("# HELP {} {}\n", name, help));
+            }
+            out.push_str(&format!("# TYPE {} {}\n", name, kind));
+            for metric in family.get_metric() {
+                encode_openmetrics_samples(&mut out, name, family.get_field_type(), metric);
+            }
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+
+    fn encode_openmetrics_samples(out: &mut String, name: &str, kind: MetricType, metric: &Metric) {
+        let mut sample = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+            let labels: Vec<String> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value().to_owned()))
+                .chain(extra)
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape_openmetrics(&value)))
+                .collect();
+            let labels = if labels.is_empty() {
+                String::new()
+            } else {
+                format!("{{{}}}", labels.join(","))
+            };
+            let value = openmetrics_float(value);
+            out.push_str(&format!("{}{}{} {}\n", name, suffix, labels, value));
+        };
+        match kind {
+            MetricType::COUNTER => sample("_total", None, metric.get_counter().get_value()),
+            MetricType::GAUGE => sample("", None, metric.get_gauge().get_value()),
+            MetricType::UNTYPED => sample("", None, metric.get_untyped().get_value()),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                let count = histogram.get_sample_count() as f64;
+                let mut has_inf = false;
+                for bucket in histogram.get_bucket() {
+                    let bound = bucket.get_upper_bound();
+                    has_inf |= bound == f64::INFINITY;
+                    let le = Some(("le", openmetrics_float(bound)));
+                    sample("_bucket", le, bucket.get_cumulative_count() as f64);
+                }
+                if !has_inf {
+                    sample("_bucket", Some(("le", "+Inf".into())), count);
+                }
+                sample("_count", None, count);
+                sample("_sum", None, histogram.get_sample_sum());
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for quantile in summary.get_quantile() {
+                    let q = Some(("quantile", openmetrics_float(quantile.get_quantile())));
+                    sample("", q, quantile.get_value());
+                }
+                sample("_count", None, summary.get_sample_count() as f64);
+                sample("_sum", None, summary.get_sample_sum());
+            }
+        }
+    }
+
+    fn escape_openmetrics(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    fn openmetrics_float(value: f64) -> String {
+        if value == f64::INFINITY {
+            "+Inf".into()
+        } else if value == f64::NEG_INFINITY {
+            "-Inf".into()
+        } else {
+            value.to_string()
+        }
+    }
+
+    // operational endpoints which would only skew the business metrics
+    const UNMETERED_PATHS: &[&str] = &["/metrics", "/health", "/ready", "/version", "/log-level"];
+
+    /// Counts a request as active for as long as it is alive, also when it is dropped before a
+    /// response could be produced.
+    pub struct InFlight;
+
+    impl InFlight {
+        pub fn start(path: &str) -> Option<Self> {
+            if UNMETERED_PATHS.contains(&path) {
+                return None;
+            }
+            METERS.active_requests.add(1, &[]);
+            Some(Self)
+        }
+    }
+
+    /// Records the size of request bodies, as announced by their `Content-Length`.
+    pub fn record_request_size(path: &str, content_length: Option<u64>) {
+        if UNMETERED_PATHS.contains(&path) {
+            return;
+        }
+        if let Some(size) = content_length {
+            let labels = [KeyValue::new("http.target", route_template(path))];
+            METERS.request_size.record(size, &labels);
+        }
+    }
+
+    /// Records the size of responses with a body of known length, streamed ones are skipped.
+    pub fn record_response_size(path: &str, response: &Response) {
+        if UNMETERED_PATHS.contains(&path) {
+            return;
+        }
+        if let Some(size) = response.body().size_hint().exact() {
+            let labels = [KeyValue::new("http.target", route_template(path))];
+            METERS.response_size.record(size, &labels);
+        }
+    }
+
+    pub fn record_error(reason: &'static str) {
+        METERS.errors.add(1, &[KeyValue::new("reason", reason)]);
+    }
+
+    pub fn record_panic() {
+        METERS.panics.add(1, &[]);
+    }
+
+    /// Records how long acquiring the lock on the in-memory users took, by `read` or `write`.
+    pub fn record_lock_wait(mode: &'static str, wait: Duration) {
+        let labels = [KeyValue::new("mode", mode)];
+        METERS
+            .lock_wait
+            .record(wait.as_secs_f64() * 1000.0, &labels);
+    }
+
+    pub fn record_users_loaded(count: usize) {
+        METERS.users_total.add(count as i64, &[]);
+    }
+
+    pub fn record_users_created(count: usize) {
+        METERS.users_created.add(count as u64, &[]);
+        METERS.users_total.add(count as i64, &[]);
+    }
+
+    pub fn record_user_deleted() {
+        METERS.users_total.add(-1, &[]);
+    }
+
+    /// Adjusts the total by the difference in active users a replacement of all users made.
+    pub fn record_users_replaced(delta: i64) {
+        METERS.users_total.add(delta, &[]);
+    }
+
+    /// Reports the active users in the store, so the value can't drift like the counters
+    /// updated by the handlers.
+    pub fn observe_users_current(repo: impl Repository) {
+        global::meter("web-service")
+            .i64_up_down_sum_observer(metric_name("users_current"), move |result| {
+                if let Some(active) = repo.count_active() {
+                    result.observe(active.total(), &[]);
+                }
+            })
+            .init();
+    }
+
+    /// Reports the active users per gender from the same count as `users_current`,
+    /// self-described genders are all counted as `other`, which keeps the labels bounded.
+    pub fn observe_users_by_gender(repo: impl Repository) {
+        global::meter("web-service")
+            .i64_up_down_sum_observer(metric_name("users_by_gender"), move |result| {
+                if let Some(active) = repo.count_active() {
+                    for (label, count) in active.by_gender() {
+                        result.observe(count, &[KeyValue::new("gender", label)]);
+                    }
+                }
+            })
+            .init();
+    }
+
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            METERS.active_requests.add(-1, &[]);
+        }
+    }
+
+    pub fn record_metrics(info: Info) {
+        if UNMETERED_PATHS.contains(&info.path()) {
+            return;
+        }
+
+        let result: Result<ServiceMetrics, _> = (&info).try_into();
+        if let Ok(metrics) = result {
+            metrics.record();
+        };
+    }
+
+    /// Emits an access record per completed request.
+    pub fn log_access(info: Info) {
+        if UNMETERED_PATHS.contains(&info.path()) {
+            return;
+        }
+
+        let remote_addr = info
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        tracing::info!(
+            http.method = %info.method(),
+            http.target = info.path(),
+            http.status_code = info.status().as_u16(),
+            duration_ms = info.elapsed().as_millis() as u64,
+            remote_addr = %remote_addr,
+            "request completed"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config::{self, ApiConfig, CorsOrigins};
+    use super::filters;
+    use super::handlers;
+    #[cfg(feature = "sqlite")]
+    use super::models::SqliteRepo;
+    use super::models::{
+        init_state, merge_patch, name_contains, ActiveUsers, DashMapRepo, Gender, InMemoryRepo,
+        NewUser, Repository, RepositoryError, SearchRequest, State, User, UserBuilder,
+    };
+    use super::observability::{
+        encode_text, extract_context, init_metrics_exporter, method_label, observe_users_by_gender,
+        observe_users_current, resource, route_template, sampler, JsonExporter, LogFilter,
+        SpanCounter, TracesExporter, TENANT_BAGGAGE,
+    };
+    use async_compression::tokio::bufread::GzipDecoder;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use futures::future::join_all;
+    use lazy_static::lazy_static;
+    use opentelemetry::baggage::BaggageExt;
+    use opentelemetry::sdk;
+    use opentelemetry::trace::{
+        Span, SpanContext, SpanId, TraceContextExt, TraceId, TraceState, Tracer, TracerProvider,
+        TRACE_FLAG_SAMPLED,
+    };
+    use opentelemetry_prometheus::PrometheusExporter;
+    use prometheus::proto::{Metric, MetricFamily, MetricType};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::io::{self, Write};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::{oneshot, RwLock, Semaphore};
+    use tracing::subscriber::DefaultGuard;
+    use tracing_bunyan_formatter as bunyan;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::{reload, EnvFilter};
+    use uuid::Uuid;
+    use warp::http::{HeaderMap, Method, Response, StatusCode};
+    use warp::hyper::body::Bytes;
+    use warp::test::request;
+    use warp::{Filter, Reply};
+
+    fn with_otel_tracing() -> (sdk::trace::TracerProvider, DefaultGuard) {
+        let provider = sdk::trace::TracerProvider::builder().build();
+        let tracer = provider.get_tracer("test", None);
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        (provider, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogBuffer {
+        fn records(&self) -> Vec<serde_json::Value> {
+            let buf = self.0.lock().unwrap();
+            String::from_utf8_lossy(&buf)
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn with_bunyan_logs() -> (LogBuffer, DefaultGuard) {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(bunyan::JsonStorageLayer)
+            .with(bunyan::BunyanFormattingLayer::new(
+                "test".into(),
+                move || writer.clone(),
+            ));
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    lazy_static! {
+        // METERS binds to the meter provider installed first, so all tests share one exporter
+        static ref METRICS_EXPORTER: PrometheusExporter = init_metrics_exporter().unwrap();
+    }
+
+    fn metrics_exporter() -> PrometheusExporter {
+        METRICS_EXPORTER.clone()
+    }
+
+    /// Sums up the current values of a metric across the series matching the given labels.
+    fn sample(name: &str, labels: &[(&str, &str)]) -> f64 {
+        METRICS_EXPORTER
+            .registry()
+            .gather()
+            .iter()
+            // the tests run with the default namespace
+            .filter(|family| family.get_name() == format!("web_service_{}", name))
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| {
+                labels.iter().all(|(name, value)| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.get_name() == *name && label.get_value() == *value)
+                })
+            })
+            .map(|metric| {
+                if metric.has_counter() {
+                    metric.get_counter().get_value()
+                } else if metric.has_gauge() {
+                    metric.get_gauge().get_value()
+                } else {
+                    metric.get_histogram().get_sample_count() as f64
+                }
+            })
+            .sum()
+    }
+
+    lazy_static! {
+        // requests hold this shared, tests asserting that a metric did not change take it
+        // exclusively
+        static ref METRICS_LOCK: RwLock<()> = RwLock::new(());
+    }
+
+    fn api(state: State) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        api_with(InMemoryRepo::new(state))
+    }
+
+    fn api_with(
+        repo: impl Repository,
+    ) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        api_with_config(repo, ApiConfig::default())
+    }
+
+    fn api_with_config(
+        repo: impl Repository,
+        config: ApiConfig,
+    ) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        warp::any()
+            .and_then(|| async { Ok::<_, Infallible>(METRICS_LOCK.read().await) })
+            .and(filters::users(repo, metrics_exporter(), &config))
+            .map(|_guard, reply| reply)
+    }
+
+    /// Serves the API on an ephemeral port, for tests which need the real HTTP stack.
+    fn spawn_api(repo: impl Repository, config: ApiConfig) -> SocketAddr {
+        let (addr, server) =
+            warp::serve(api_with_config(repo, config)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    fn user(id: u64) -> User {
+        UserBuilder::new(id, "Doe").build()
+    }
+
+    async fn list_ids(
+        api: &(impl Filter<Extract = impl Reply, Error = Infallible> + 'static),
+        path: &str,
+    ) -> Vec<u64> {
+        let response = request().method("GET").path(path).reply(api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let users: Vec<User> = serde_json::from_slice(response.body()).unwrap();
+        users.iter().map(|user| user.id).collect()
+    }
+
+    #[tokio::test]
+    async fn get_users() {
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/users").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn list_users_default_page() {
+        let state = init_state();
+        state.write().await.extend((0..60).map(|id| (id, user(id))));
+        let api = api(state);
+
+        let ids = list_ids(&api, "/users").await;
+
+        assert_eq!(ids, (0..50).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn list_users_explicit_page() {
+        let state = init_state();
+        state.write().await.extend((0..60).map(|id| (id, user(id))));
+        let api = api(state);
+
+        let ids = list_ids(&api, "/users?limit=5&offset=10").await;
+
+        assert_eq!(ids, (10..15).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn list_users_clamps_limit() {
+        let state = init_state();
+        state
+            .write()
+            .await
+            .extend((0..300).map(|id| (id, user(id))));
+        let api = api(state);
+
+        let ids = list_ids(&api, "/users?limit=1000").await;
+
+        assert_eq!(ids.len(), 200);
+    }
+
+    #[tokio::test]
+    async fn list_users_offset_out_of_range() {
+        let state = init_state();
+        state.write().await.extend((0..3).map(|id| (id, user(id))));
+        let api = api(state);
+
+        let ids = list_ids(&api, "/users?offset=10").await;
+
+        assert!(ids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_reads() {
+        let state = init_state();
+        state.write().await.insert(1, user(1));
+        let api = api(state.clone());
+
+        let guard = state.read().await;
+        let reads = (0..8).map(|_| {
+            let api = api.clone();
+            tokio::spawn(async move { list_ids(&api, "/users").await })
+        });
+        let ids = tokio::time::timeout(Duration::from_secs(1), join_all(reads))
+            .await
+            .expect("reads should not block each other");
+        assert!(ids.into_iter().all(|ids| ids.unwrap() == [1]));
+
+        assert!(state.try_write().is_err());
+        drop(guard);
+        let write_guard = state.try_write().unwrap();
+        assert!(state.try_read().is_err());
+        drop(write_guard);
+    }
+
+    #[tokio::test]
+    async fn list_users_by_gender() {
+        let state = init_state();
+        let genders = [Gender::Female, Gender::Male, Gender::Unspecified];
+        state
+            .write()
+            .await
+            .extend(genders.iter().enumerate().map(|(id, gender)| {
+                let id = id as u64;
+                let user = UserBuilder::new(id, "Doe").gender(gender.clone()).build();
+                (id, user)
+            }));
+        let api = api(state);
+
+        assert_eq!(list_ids(&api, "/users?gender=female").await, [0]);
+        assert_eq!(list_ids(&api, "/users?gender=male").await, [1]);
+        assert_eq!(list_ids(&api, "/users?gender=unspecified").await, [2]);
+        assert_eq!(list_ids(&api, "/users").await, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn list_users_by_id_range() {
+        let state = init_state();
+        state.write().await.extend((1..=6).map(|id| (id, user(id))));
+        let api = api(state);
+
+        assert_eq!(list_ids(&api, "/users?minId=2&maxId=4").await, [2, 3, 4]);
+        assert_eq!(list_ids(&api, "/users?minId=3&maxId=3").await, [3]);
+        assert_eq!(list_ids(&api, "/users?minId=5").await, [5, 6]);
+        assert_eq!(list_ids(&api, "/users?maxId=2").await, [1, 2]);
+        assert_eq!(
+            list_ids(&api, "/users?minId=2&maxId=5&offset=1&limit=2").await,
+            [3, 4]
+        );
+    }
+
+    #[tokio::test]
+    async fn list_users_inverted_id_range() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("GET")
+            .path("/users?minId=5&maxId=4")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(body.contains("minId 5 must not exceed maxId 4"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn list_users_invalid_gender() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("GET")
+            .path("/users?gender=%20")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(body.contains("gender must not be blank"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn list_users_as_ndjson() {
+        let state = init_state();
+        state.write().await.extend((1..=3).map(|id| (id, user(id))));
+        let api = api(state);
+
+        let json = request().method("GET").path("/users").reply(&api).await;
+        let ndjson = request()
+            .method("GET")
+            .path("/users")
+            .header("accept", "application/x-ndjson")
+            .reply(&api)
+            .await;
+
+        assert_eq!(ndjson.status(), StatusCode::OK);
+        assert_eq!(ndjson.headers()["content-type"], "application/x-ndjson");
+        let lines: Vec<serde_json::Value> = String::from_utf8_lossy(ndjson.body())
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let array: Vec<serde_json::Value> = serde_json::from_slice(json.body()).unwrap();
+        assert_eq!(json.headers()["content-type"], "application/json");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines, array);
+    }
+
+    #[test]
+    fn users_as_csv() {
+        let mut quoted = user(2);
+        quoted.first_name = Some("Jane, Jr.".into());
+        quoted.last_name = "\"JD\" Doe".into();
+        quoted.gender = Gender::Other("two-spirit".into());
+
+        let csv = handlers::csv(&[user(1), quoted]);
+
+        assert_eq!(
+            csv,
+            "id,firstName,lastName,gender\r\n\
+             1,,Doe,unspecified\r\n\
+             2,\"Jane, Jr.\",\"\"\"JD\"\" Doe\",two-spirit\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn count_users() {
+        let state = init_state();
+        state.write().await.extend((1..=5).map(|id| {
+            let mut user = user(id);
+            if id % 2 == 0 {
+                user.gender = Gender::Female;
+            }
+            if id == 5 {
+                user.deleted_at = Some(Utc::now());
+            }
+            (id, user)
+        }));
+        let api = api(state);
+        let count = |path: &'static str| {
+            let api = api.clone();
+            async move {
+                let response = request().method("GET").path(path).reply(&api).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+                body["count"].as_u64().unwrap()
+            }
+        };
+
+        assert_eq!(count("/users/count").await, 4);
+        assert_eq!(count("/users/count?gender=female").await, 2);
+        assert_eq!(count("/users/count?includeDeleted=true").await, 5);
+    }
+
+    #[tokio::test]
+    async fn list_users_as_csv() {
+        let state = init_state();
+        state.write().await.extend((1..=2).map(|id| (id, user(id))));
+        let api = api(state);
+
+        let response = request()
+            .method("GET")
+            .path("/users")
+            .header("accept", "text/csv")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        let body = String::from_utf8_lossy(response.body());
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "id,firstName,lastName,gender");
+    }
+
+    #[tokio::test]
+    async fn create_user() {
+        let state = init_state();
+        let api = api(state.clone());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(
+                r#"{
+                    "firstName": "Jane",
+                    "lastName": "Doe",
+                    "gender": "female",
+                    "id": 123
+                }"#,
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let users = state.read().await;
+        let stored = &users[&1];
+        assert_eq!(created, serde_json::to_value(stored).unwrap());
+        assert_eq!(
+            *stored,
+            User {
+                id: 1,
+                first_name: Some("Jane".into()),
+                last_name: "Doe".into(),
+                gender: Gender::Female,
+                email: None,
+                created_at: stored.created_at,
+                updated_at: stored.updated_at,
+                version: 1,
+                deleted_at: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn create_user_ids_are_monotonic() {
+        let api = api(init_state());
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let response = request()
+                .method("POST")
+                .path("/users")
+                .body(r#"{"lastName": "Doe", "gender": "male", "id": 7}"#)
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let user: User = serde_json::from_slice(response.body()).unwrap();
+            ids.push(user.id);
+        }
+
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn create_user_without_gender() {
+        let state = init_state();
+        let api = api(state.clone());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(created["gender"], "unspecified");
+        assert_eq!(state.read().await[&1].gender, Gender::Unspecified);
+    }
+
+    #[tokio::test]
+    async fn create_user_duplicate_id() {
+        let state = init_state();
+        state.write().await.insert(1, user(1));
+        let api = api(state.clone());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe", "gender": "male"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "conflict");
+        assert_eq!(body["error"]["message"], "user id already exists");
+        assert_eq!(state.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_user() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state);
+
+        let response = request().method("GET").path("/users/42").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let user: User = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(user.id, 42);
+    }
+
+    #[tokio::test]
+    async fn get_user_not_modified_since() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state);
+
+        let response = request().method("GET").path("/users/42").reply(&api).await;
+        let last_modified = response.headers()["last-modified"].clone();
+        let unchanged = request()
+            .method("GET")
+            .path("/users/42")
+            .header("if-modified-since", last_modified.clone())
+            .reply(&api)
+            .await;
+
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert!(unchanged.body().is_empty());
+        assert_eq!(unchanged.headers()["last-modified"], last_modified);
+    }
+
+    #[tokio::test]
+    async fn get_user_modified_since() {
+        let state = init_state();
+        let mut stored = user(42);
+        stored.updated_at = "2021-03-04T05:06:07Z".parse().unwrap();
+        state.write().await.insert(42, stored);
+        let api = api(state);
+
+        let response = request()
+            .method("GET")
+            .path("/users/42")
+            .header("if-modified-since", "Thu, 04 Mar 2021 05:06:06 GMT")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["last-modified"],
+            "Thu, 04 Mar 2021 05:06:07 GMT"
+        );
+        let user: User = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(user.id, 42);
+    }
+
+    #[tokio::test]
+    async fn get_unknown_user() {
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/users/42").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn replace_user() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PUT")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"id": 42, "lastName": "Roe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let users = state.read().await;
+        assert_eq!(users[&42].last_name, "Roe");
+    }
+
+    #[tokio::test]
+    async fn replace_user_id_mismatch() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("PUT")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"id": 43, "lastName": "Roe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn replace_unknown_user() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("PUT")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"id": 42, "lastName": "Roe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn patch_user() {
+        let state = init_state();
+        state.write().await.insert(
+            42,
+            User {
+                first_name: Some("Jane".into()),
+                gender: Gender::Female,
+                ..user(42)
+            },
+        );
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PATCH")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"lastName": "Roe"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let user: User = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(user.first_name.as_deref(), Some("Jane"));
+        assert_eq!(user.last_name, "Roe");
+        assert!(matches!(user.gender, Gender::Female));
+    }
+
+    #[tokio::test]
+    async fn patch_user_clear_first_name() {
+        let state = init_state();
+        state.write().await.insert(
+            42,
+            User {
+                first_name: Some("Jane".into()),
+                gender: Gender::Female,
+                ..user(42)
+            },
+        );
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PATCH")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"firstName": null, "gender": "unspecified"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let users = state.read().await;
+        assert_eq!(users[&42].first_name, None);
+        assert_eq!(users[&42].last_name, "Doe");
+        assert!(matches!(users[&42].gender, Gender::Unspecified));
+    }
+
+    #[tokio::test]
+    async fn merge_patch_user() {
+        let state = init_state();
+        state.write().await.insert(
+            42,
+            User {
+                first_name: Some("Jane".into()),
+                gender: Gender::Female,
+                email: Some("jane@example.com".into()),
+                ..user(42)
+            },
+        );
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PATCH")
+            .path("/users/42")
+            .header("content-type", "application/merge-patch+json")
+            .header("if-match", "1")
+            .body(r#"{"firstName": null, "lastName": "Roe"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body.get("firstName").is_none());
+        assert_eq!(body["version"], 2);
+        let users = state.read().await;
+        assert_eq!(users[&42].first_name, None);
+        assert_eq!(users[&42].last_name, "Roe");
+        assert_eq!(users[&42].gender, Gender::Female);
+        assert_eq!(users[&42].email.as_deref(), Some("jane@example.com"));
+    }
+
+    #[tokio::test]
+    async fn merge_patch_user_invalid() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state);
+        let merge_patch = |body: &'static str| {
+            request()
+                .method("PATCH")
+                .path("/users/42")
+                .header("content-type", "application/merge-patch+json")
+                .header("if-match", "1")
+                .body(body)
+                .reply(&api)
+        };
+
+        let removed = merge_patch(r#"{"lastName": null}"#).await;
+        let malformed = merge_patch("{").await;
+
+        assert_eq!(removed.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(removed.body()).unwrap();
+        assert_eq!(body["error"]["code"], "invalid_patch");
+        assert_eq!(malformed.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn json_patch_user() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state.clone());
+        let json_patch = |body: &'static str| {
+            request()
+                .method("PATCH")
+                .path("/users/42")
+                .header("content-type", "application/json-patch+json")
+                .header("if-match", "1")
+                .body(body)
+                .reply(&api)
+        };
+
+        let replaced = json_patch(
+            r#"[
+                {"op": "test", "path": "/lastName", "value": "Doe"},
+                {"op": "replace", "path": "/lastName", "value": "Roe"},
+                {"op": "add", "path": "/firstName", "value": "Jane"}
+            ]"#,
+        )
+        .await;
+
+        assert_eq!(replaced.status(), StatusCode::OK);
+        let users = state.read().await;
+        assert_eq!(users[&42].last_name, "Roe");
+        assert_eq!(users[&42].first_name.as_deref(), Some("Jane"));
+    }
+
+    #[tokio::test]
+    async fn json_patch_user_failing_test() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PATCH")
+            .path("/users/42")
+            .header("content-type", "application/json-patch+json")
+            .header("if-match", "1")
+            .body(
+                r#"[
+                    {"op": "replace", "path": "/lastName", "value": "Roe"},
+                    {"op": "test", "path": "/gender", "value": "female"}
+                ]"#,
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "invalid_patch");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains("'/1'"), "{}", message);
+        assert!(message.contains("/gender"), "{}", message);
+        assert_eq!(state.read().await[&42].last_name, "Doe");
+    }
+
+    #[test]
+    fn merge_patch_rfc_7386() {
+        let mut target = serde_json::json!({"a": "b", "c": {"d": "e", "f": "g"}});
+
+        merge_patch(
+            &mut target,
+            &serde_json::json!({"a": "z", "c": {"f": null}, "h": [1]}),
+        );
+
+        assert_eq!(
+            target,
+            serde_json::json!({"a": "z", "c": {"d": "e"}, "h": [1]})
+        );
+    }
+
+    #[tokio::test]
+    async fn patch_unknown_user() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("PATCH")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"lastName": "Roe"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn create_user_sets_timestamps() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe", "gender": "female", "createdAt": "2000-01-01T00:00:00Z"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let created_at: DateTime<Utc> = body["createdAt"].as_str().unwrap().parse().unwrap();
+        assert!(Utc::now() - created_at < chrono::Duration::minutes(1));
+        assert_eq!(body["updatedAt"], body["createdAt"]);
+    }
+
+    #[tokio::test]
+    async fn create_users_bulk() {
+        let state = init_state();
+        let api = api(state.clone());
+
+        let response = request()
+            .method("POST")
+            .path("/users/batch")
+            .body(
+                r#"[
+                    {"firstName": "Jane", "lastName": "Doe", "gender": "female"},
+                    {"lastName": "Roe", "gender": "male"}
+                ]"#,
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let users: Vec<User> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(users.iter().map(|user| user.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(users[1].last_name, "Roe");
+        assert_eq!(state.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn create_users_bulk_with_invalid_element() {
+        let state = init_state();
+        let api = api(state.clone());
+
+        let response = request()
+            .method("POST")
+            .path("/users/batch")
+            .body(
+                r#"[
+                    {"lastName": "Doe", "gender": "female"},
+                    {"lastName": " ", "gender": "male"}
+                ]"#,
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["details"][0]["field"], "lastName");
+        assert!(state.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replace_users() {
+        let _exclusive = METRICS_LOCK.write().await;
+        let state = init_state();
+        state.write().await.extend((1..=3).map(|id| (id, user(id))));
+        let api = filters::users(
+            InMemoryRepo::new(state),
+            metrics_exporter(),
+            &ApiConfig::default(),
+        );
+        let total = sample("users_total", &[]);
+
+        let response = request()
+            .method("PUT")
+            .path("/users")
+            .body(
+                r#"[
+                    {"id": 7, "lastName": "Doe", "gender": "female"},
+                    {"id": 5, "lastName": "Roe", "gender": "male"}
+                ]"#,
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let users: Vec<User> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(users.iter().map(|user| user.id).collect::<Vec<_>>(), [5, 7]);
+        assert_eq!(users[0].last_name, "Roe");
+        assert_eq!(list_ids(&api, "/users").await, [5, 7]);
+        assert_eq!(sample("users_total", &[]), total - 1.);
+    }
+
+    #[tokio::test]
+    async fn replace_users_with_invalid_element() {
+        let state = init_state();
+        state.write().await.extend((1..=3).map(|id| (id, user(id))));
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PUT")
+            .path("/users")
+            .body(
+                r#"[
+                    {"id": 7, "lastName": "Doe", "gender": "female"},
+                    {"id": 5, "lastName": " ", "gender": "male"}
+                ]"#,
+            )
+            .reply(&api)
+            .await;
+        let duplicate = request()
+            .method("PUT")
+            .path("/users")
+            .body(
+                r#"[
+                    {"id": 7, "lastName": "Doe", "gender": "female"},
+                    {"id": 7, "lastName": "Roe", "gender": "male"}
+                ]"#,
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["details"][0]["field"], "lastName");
+        assert_eq!(duplicate.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(duplicate.body()).unwrap();
+        assert_eq!(body["error"]["details"][0]["field"], "id");
+        assert_eq!(list_ids(&api, "/users").await, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn patch_user_updates_timestamp() {
+        let state = init_state();
+        let an_hour_ago = Utc::now() - chrono::Duration::hours(1);
+        state.write().await.insert(
+            42,
+            User {
+                created_at: an_hour_ago,
+                updated_at: an_hour_ago,
+                ..user(42)
+            },
+        );
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PATCH")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"lastName": "Roe", "createdAt": "2000-01-01T00:00:00Z"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let created_at: DateTime<Utc> = body["createdAt"].as_str().unwrap().parse().unwrap();
+        let updated_at: DateTime<Utc> = body["updatedAt"].as_str().unwrap().parse().unwrap();
+        assert_eq!(created_at, an_hour_ago);
+        assert!(updated_at > an_hour_ago);
+        assert_eq!(state.read().await[&42].updated_at, updated_at);
+    }
+
+    #[tokio::test]
+    async fn delete_user() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state.clone());
+
+        let response = request()
+            .method("DELETE")
+            .path("/users/42")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.read().await[&42].deleted_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn delete_unknown_user() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("DELETE")
+            .path("/users/42")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unsupported_method_on_users() {
+        let config = ApiConfig {
+            api_token: Some("secret".into()),
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(InMemoryRepo::new(init_state()), config);
+
+        let response = request().method("DELETE").path("/users").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()["allow"], "GET, HEAD, POST, PUT");
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "method_not_allowed");
+    }
+
+    #[tokio::test]
+    async fn wrong_body() {
+        let state = init_state();
+        let api = api(state.clone());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(
+                r#"{
+                    "invalid": 123
+                }"#,
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn repository_contract(repo: impl Repository) {
+        let new_user = || NewUser {
+            first_name: Some("Jane".into()),
+            last_name: "Doe".into(),
+            gender: Gender::Female,
+            email: None,
+        };
+
+        let first = repo.create(new_user()).await.unwrap();
+        let second = repo.create(new_user()).await.unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(repo.list().await.unwrap().len(), 2);
+        assert_eq!(repo.get(first.id).await.unwrap().last_name, "Doe");
+
+        let renamed = User {
+            last_name: "Roe".into(),
+            gender: Gender::Male,
+            ..first.clone()
+        };
+        let renamed = repo.update(renamed).await.unwrap();
+        assert_eq!(renamed.version, first.version + 1);
+        let active = repo.count_active().unwrap();
+        assert_eq!(active.total(), 2);
+        assert!(active.by_gender().any(|count| count == ("male", 1)));
+        assert_eq!(repo.get(first.id).await.unwrap().last_name, "Roe");
+        assert_eq!(
+            repo.update(first.clone()).await.unwrap_err(),
+            RepositoryError::Stale
+        );
+
+        repo.delete(first.id).await.unwrap();
+        assert_eq!(
+            repo.get(first.id).await.unwrap_err(),
+            RepositoryError::NotFound
+        );
+        assert_eq!(
+            repo.delete(first.id).await.unwrap_err(),
+            RepositoryError::NotFound
+        );
+        assert_eq!(
+            repo.update(first).await.unwrap_err(),
+            RepositoryError::NotFound
+        );
+        let users = repo.list().await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users.iter().filter(|u| u.deleted_at.is_none()).count(), 1);
+        assert_eq!(repo.count_active(), Some(ActiveUsers::count(&users)));
+
+        let batch = repo
+            .create_many(vec![new_user(), new_user()])
+            .await
+            .unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_ne!(batch[0].id, batch[1].id);
+        assert_eq!(repo.list().await.unwrap().len(), 4);
+
+        let replaced = repo.replace_all(vec![user(20), user(10)]).await.unwrap();
+        let ids: Vec<u64> = replaced.iter().map(|user| user.id).collect();
+        assert_eq!(ids, [10, 20]);
+        assert_eq!(repo.list().await.unwrap(), replaced);
+        assert_eq!(repo.create(new_user()).await.unwrap().id, 21);
+        assert_eq!(repo.count_active().unwrap().total(), 3);
+    }
+
+    #[tokio::test]
+    async fn in_memory_repo_contract() {
+        repository_contract(InMemoryRepo::new(init_state())).await;
+    }
+
+    #[tokio::test]
+    async fn dashmap_repo_contract() {
+        repository_contract(DashMapRepo::new()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn dashmap_repo_concurrent_creates() {
+        let repo = DashMapRepo::new();
+
+        let creates = (0..200).map(|i| {
+            let repo = repo.clone();
+            tokio::spawn(async move {
+                repo.create(NewUser {
+                    first_name: None,
+                    last_name: format!("Doe {}", i),
+                    gender: Gender::Unspecified,
+                    email: None,
+                })
+                .await
+                .unwrap()
+            })
+        });
+        let created: Vec<User> = join_all(creates)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        let users = repo.list().await.unwrap();
+        assert_eq!(users.len(), 200);
+        let ids: Vec<u64> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, (1..=200).collect::<Vec<_>>());
+        assert!(created.iter().all(|user| users.contains(user)));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sqlite_repo_contract() {
+        let repo = SqliteRepo::connect("sqlite::memory:").await.unwrap();
+        repository_contract(repo).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sqlite_repo_serves_users() {
+        let repo = SqliteRepo::connect("sqlite::memory:").await.unwrap();
+        let api = api_with(repo);
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"firstName": "Jane", "lastName": "Doe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: User = serde_json::from_slice(response.body()).unwrap();
+
+        let response = request()
+            .method("GET")
+            .path(&format!("/users/{}", created.id))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let user: User = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(user.first_name.as_deref(), Some("Jane"));
+        assert_eq!(user.gender, Gender::Female);
+    }
+
+    #[test]
+    fn traces_exporter_selection() {
+        #[cfg(feature = "jaeger")]
+        {
+            assert_eq!(TracesExporter::parse(None).unwrap(), TracesExporter::Jaeger);
+            assert_eq!(
+                TracesExporter::parse(Some("jaeger")).unwrap(),
+                TracesExporter::Jaeger
+            );
+        }
+        #[cfg(feature = "otlp")]
+        assert_eq!(
+            TracesExporter::parse(Some("otlp")).unwrap(),
+            TracesExporter::Otlp
+        );
+        #[cfg(all(feature = "otlp", not(feature = "jaeger")))]
+        assert_eq!(TracesExporter::parse(None).unwrap(), TracesExporter::Otlp);
+        #[cfg(not(feature = "jaeger"))]
+        assert_eq!(
+            TracesExporter::parse(Some("jaeger"))
+                .unwrap_err()
+                .to_string(),
+            "OTEL_TRACES_EXPORTER jaeger needs a build with the jaeger feature"
+        );
+        #[cfg(not(feature = "otlp"))]
+        assert!(TracesExporter::parse(Some("otlp")).is_err());
+        #[cfg(feature = "stdout")]
+        assert_eq!(
+            TracesExporter::parse(Some("stdout")).unwrap(),
+            TracesExporter::Stdout
+        );
+        assert!(TracesExporter::parse(Some("zipkin")).is_err());
+    }
+
+    #[cfg(feature = "stdout")]
+    #[test]
+    fn install_stdout_tracer() {
+        // no collector is listening, the spans are only printed
+        let tracer =
+            super::observability::install_tracer(TracesExporter::Stdout, "test", 1.).unwrap();
+        let span = tracer.start("stdout");
+        assert!(span.span_context().is_valid());
+    }
+
+    #[test]
+    fn parse_service_name() {
+        assert_eq!(config::parse_service_name(None), env!("CARGO_PKG_NAME"));
+        assert_eq!(
+            config::parse_service_name(Some("  ")),
+            env!("CARGO_PKG_NAME")
+        );
+        assert_eq!(config::parse_service_name(Some("users-api")), "users-api");
+    }
+
+    #[test]
+    fn resource_attributes() {
+        let resource = resource();
+        let keys: Vec<&str> = resource.iter().map(|(key, _)| key.as_str()).collect();
+
+        assert!(keys.contains(&"service.name"));
+        assert!(keys.contains(&"service.version"));
+        assert!(keys.contains(&"host.name"));
+    }
+
+    #[test]
+    fn extract_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let cx = extract_context(&headers);
+
+        let span_context = cx.remote_span_context().unwrap();
+        assert_eq!(
+            span_context.trace_id().to_hex(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(span_context.span_id().to_hex(), "b7ad6b7169203331");
+    }
+
+    #[tokio::test]
+    async fn trace_id_header() {
+        let _tracing = with_otel_tracing();
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/users").reply(&api).await;
+
+        let trace_id = response.headers()["x-trace-id"].to_str().unwrap();
+        assert_eq!(trace_id.len(), 32);
+        assert!(trace_id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[tokio::test]
+    async fn trace_id_header_continues_caller_trace() {
+        let _tracing = with_otel_tracing();
+        let api = api(init_state());
+
+        let response = request()
+            .method("GET")
+            .path("/users")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            response.headers()["x-trace-id"],
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+    }
+
+    #[tokio::test]
+    async fn trace_id_header_omitted_without_span() {
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/users").reply(&api).await;
+
+        assert!(response.headers().get("x-trace-id").is_none());
+    }
+
+    #[tokio::test]
+    async fn request_id_is_generated() {
+        let (logs, _guard) = with_bunyan_logs();
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/users").reply(&api).await;
+
+        let request_id = response.headers()["x-request-id"].to_str().unwrap();
+        assert!(Uuid::parse_str(request_id).is_ok(), "{}", request_id);
+        let records = logs.records();
+        let end = records
+            .iter()
+            .find(|record| record["msg"] == "[REQUEST - END]")
+            .unwrap();
+        assert_eq!(end["request_id"], request_id);
+    }
+
+    #[tokio::test]
+    async fn request_id_is_taken_over() {
+        let api = api(init_state());
+        let request_id = |id: &'static str| {
+            let api = api.clone();
+            async move {
+                let response = request()
+                    .method("GET")
+                    .path("/users")
+                    .header("x-request-id", id)
+                    .reply(&api)
+                    .await;
+                response.headers()["x-request-id"]
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            }
+        };
+
+        assert_eq!(request_id("req-42").await, "req-42");
+        let replaced = request_id("not an id").await;
+        assert!(Uuid::parse_str(&replaced).is_ok(), "{}", replaced);
+    }
+
+    #[tokio::test]
+    async fn tenant_in_baggage() {
+        let _tracing = with_otel_tracing();
+        let api = warp::any()
+            .map(|| {
+                let cx = tracing::Span::current().context();
+                let tenant = cx.baggage().get(TENANT_BAGGAGE).map(ToString::to_string);
+                tenant.unwrap_or_default()
+            })
+            .with(filters::trace_request());
+
+        let response = request()
+            .method("GET")
+            .path("/users")
+            .header("x-tenant-id", "acme")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.body(), "acme");
+    }
+
+    #[tokio::test]
+    async fn require_tenant() {
+        let config = ApiConfig {
+            require_tenant: true,
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(InMemoryRepo::new(init_state()), config);
+
+        let without = request().method("GET").path("/users").reply(&api).await;
+        let with = request()
+            .method("GET")
+            .path("/users")
+            .header("x-tenant-id", "acme")
+            .reply(&api)
+            .await;
+        let health = request().method("GET").path("/health").reply(&api).await;
+
+        assert_eq!(without.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(without.body()).unwrap();
+        assert_eq!(body["error"]["code"], "missing_tenant");
+        assert_eq!(with.status(), StatusCode::OK);
+        assert_eq!(health.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn parse_flag() {
+        assert!(!config::parse_flag("FLAG", None).unwrap());
+        assert!(!config::parse_flag("FLAG", Some("false")).unwrap());
+        assert!(config::parse_flag("FLAG", Some("true")).unwrap());
+        assert!(config::parse_flag("FLAG", Some("1")).unwrap());
+        assert!(config::parse_flag("FLAG", Some("yes")).is_err());
+    }
+
+    #[tokio::test]
+    async fn logs_carry_trace_context() {
+        let provider = sdk::trace::TracerProvider::builder().build();
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(bunyan::JsonStorageLayer)
+            .with(bunyan::BunyanFormattingLayer::new(
+                "test".into(),
+                move || writer.clone(),
+            ))
+            .with(tracing_opentelemetry::layer().with_tracer(provider.get_tracer("test", None)));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let api = api(init_state());
+
+        request()
+            .method("GET")
+            .path("/users")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .reply(&api)
+            .await;
+
+        let records = logs.records();
+        let processing = records
+            .iter()
+            .find(|record| record["msg"] == "[REQUEST - EVENT] processing request")
+            .unwrap();
+        assert_eq!(processing["trace_id"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(processing["span_id"].as_str().unwrap().len(), 16);
+    }
+
+    #[tokio::test]
+    async fn health() {
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/health").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), r#"{"status":"ok"}"#);
+    }
+
+    #[tokio::test]
+    async fn version() {
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/version").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["name"], env!("CARGO_PKG_NAME"));
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["gitSha"].as_str(), option_env!("GIT_SHA"));
+        let features = body["features"].as_array().unwrap();
+        assert_eq!(
+            features.contains(&"jaeger".into()),
+            cfg!(feature = "jaeger")
+        );
+        assert_eq!(features.contains(&"otlp".into()), cfg!(feature = "otlp"));
+        assert_eq!(
+            features.contains(&"stdout".into()),
+            cfg!(feature = "stdout")
+        );
+        assert_eq!(
+            features.contains(&"sqlite".into()),
+            cfg!(feature = "sqlite")
+        );
+        assert_eq!(features.contains(&"tls".into()), cfg!(feature = "tls"));
+    }
+
+    #[derive(Clone)]
+    struct UnavailableRepo;
+
+    #[async_trait]
+    impl Repository for UnavailableRepo {
+        fn count_active(&self) -> Option<ActiveUsers> {
+            None
+        }
+
+        async fn list(&self) -> Result<Vec<User>, RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+
+        async fn get(&self, _id: u64) -> Result<User, RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+
+        async fn create(&self, _new_user: NewUser) -> Result<User, RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+
+        async fn create_many(
+            &self,
+            _new_users: Vec<NewUser>,
+        ) -> Result<Vec<User>, RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+
+        async fn replace_all(&self, _users: Vec<User>) -> Result<Vec<User>, RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+
+        async fn update(&self, _user: User) -> Result<User, RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+
+        async fn delete(&self, _id: u64) -> Result<(), RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+
+        async fn ping(&self) -> Result<(), RepositoryError> {
+            Err(RepositoryError::Storage("unavailable".into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn ready() {
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/ready").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), r#"{"status":"ready"}"#);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn ready_sqlite() {
+        let repo = SqliteRepo::connect("sqlite::memory:").await.unwrap();
+        let api = api_with(repo);
+
+        let response = request().method("GET").path("/ready").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn not_ready() {
+        let api = api_with(UnavailableRepo);
+
+        let response = request().method("GET").path("/ready").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["dependency"], "storage");
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(super::serve(
+            InMemoryRepo::new(init_state()),
+            metrics_exporter(),
+            ApiConfig::default(),
+            ([127, 0, 0, 1], 0).into(),
+            None,
+            async {
+                rx.await.ok();
+            },
+        ));
+
+        tx.send(()).unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server should stop after the shutdown signal");
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn list_users_over_tcp() {
+        let state = init_state();
+        state.write().await.extend((1..=2).map(|id| (id, user(id))));
+        let addr = spawn_api(InMemoryRepo::new(state), ApiConfig::default());
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/users", addr))
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["x-total-count"], "2");
+        let body = gunzip(&response.bytes().await.unwrap()).await;
+        let users: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(users.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn user_events_after_subscription() {
+        let addr = spawn_api(InMemoryRepo::new(init_state()), ApiConfig::default());
+        let client = reqwest::Client::new();
+        let create = |last_name: &'static str| {
+            client
+                .post(format!("http://{}/users", addr))
+                .body(format!(
+                    r#"{{"lastName": "{}", "gender": "male"}}"#,
+                    last_name
+                ))
+                .send()
+        };
+        create("Doe").await.unwrap();
+
+        let mut events = client
+            .get(format!("http://{}/users/events", addr))
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(events.headers()["content-type"], "text/event-stream");
+        assert!(!events.headers().contains_key("content-encoding"));
+        create("Roe").await.unwrap();
+
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), events.chunk())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        assert!(received.starts_with("event:created\ndata:"), "{}", received);
+        let data = received.lines().nth(1).unwrap().trim_start_matches("data:");
+        let user: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(user["id"], 2);
+        assert_eq!(user["lastName"], "Roe");
+    }
+
+    #[tokio::test]
+    async fn flush_within_timeout() {
+        let flushed = Arc::new(AtomicBool::new(false));
+        let flag = flushed.clone();
+
+        let finished = super::flush_with_timeout(Duration::from_secs(5), move || {
+            flag.store(true, Ordering::SeqCst)
+        })
+        .await;
+
+        assert!(finished);
+        assert!(flushed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn flush_gives_up_after_timeout() {
+        let started = Instant::now();
+
+        let finished = super::flush_with_timeout(Duration::from_millis(50), || {
+            std::thread::sleep(Duration::from_secs(5))
+        })
+        .await;
+
+        assert!(!finished);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn span_counter_counts_closed_spans() {
+        let counter = SpanCounter::default();
+        let subscriber = tracing_subscriber::registry().with(counter.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let open = tracing::info_span!("open");
+        tracing::info_span!("first").in_scope(|| {});
+        tracing::info_span!("second").in_scope(|| {});
+
+        assert_eq!(counter.count(), 2);
+        drop(open);
+        assert_eq!(counter.count(), 3);
+    }
+
+    #[test]
+    fn shutdown_summary_is_logged() {
+        let (logs, _guard) = with_bunyan_logs();
+
+        super::ShutdownSummary {
+            spans: 42,
+            flushed: true,
+            duration: Duration::from_millis(1500),
+        }
+        .log();
+
+        let records = logs.records();
+        let summary = records.last().unwrap();
+        assert_eq!(summary["msg"], "shutdown complete");
+        assert_eq!(summary["spans.ended"], 42);
+        assert_eq!(summary["spans.flushed"], true);
+        assert_eq!(summary["duration_ms"], 1500);
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_with_admin_port() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let config = ApiConfig {
+            admin_port: Some(0),
+            ..ApiConfig::default()
+        };
+        let server = tokio::spawn(super::serve(
+            InMemoryRepo::new(init_state()),
+            metrics_exporter(),
+            config,
+            ([127, 0, 0, 1], 0).into(),
+            None,
+            async {
+                rx.await.ok();
+            },
+        ));
+
+        tx.send(()).unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("both servers should stop after the shutdown signal");
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn metrics_on_admin_port() {
+        let config = ApiConfig {
+            admin_port: Some(9090),
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(InMemoryRepo::new(init_state()), config);
+        let admin = filters::admin(metrics_exporter(), None);
+
+        let public = request().method("GET").path("/metrics").reply(&api).await;
+        let users = request().method("GET").path("/users").reply(&api).await;
+        let scraped = request().method("GET").path("/metrics").reply(&admin).await;
+        let health = request().method("GET").path("/health").reply(&admin).await;
+        let version = request().method("GET").path("/version").reply(&admin).await;
+        let no_users = request().method("GET").path("/users").reply(&admin).await;
+
+        assert_eq!(public.status(), StatusCode::NOT_FOUND);
+        assert_eq!(users.status(), StatusCode::OK);
+        assert_eq!(scraped.status(), StatusCode::OK);
+        assert!(String::from_utf8_lossy(scraped.body()).contains("# TYPE"));
+        assert_eq!(health.status(), StatusCode::OK);
+        assert_eq!(version.status(), StatusCode::OK);
+        assert_eq!(no_users.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn parse_admin_port() {
+        assert_eq!(config::parse_admin_port(None).unwrap(), None);
+        assert_eq!(config::parse_admin_port(Some("9090")).unwrap(), Some(9090));
+        assert!(config::parse_admin_port(Some("admin")).is_err());
+    }
+
+    #[test]
+    fn parse_tls_config() {
+        assert_eq!(config::parse_tls_config(None, None).unwrap(), None);
+        let tls = config::parse_tls_config(Some("cert.pem"), Some("key.pem")).unwrap();
+        assert_eq!(
+            tls,
+            Some(config::TlsConfig {
+                cert_path: "cert.pem".into(),
+                key_path: "key.pem".into(),
+            })
+        );
+        let err = config::parse_tls_config(Some("cert.pem"), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "TLS_CERT is set without TLS_KEY, HTTPS needs both"
+        );
+        let err = config::parse_tls_config(None, Some("key.pem")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "TLS_KEY is set without TLS_CERT, HTTPS needs both"
+        );
+    }
+
+    #[test]
+    fn check_pem() {
+        let dir = std::env::temp_dir();
+        let pem = dir.join(format!("check-pem-{}.pem", std::process::id()));
+        let der = dir.join(format!("check-pem-{}.der", std::process::id()));
+        std::fs::write(
+            &pem,
+            "-----BEGIN CERTIFICATE-----\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        std::fs::write(&der, [0x30, 0x82]).unwrap();
+
+        let valid = config::check_pem("TLS_CERT", &pem);
+        let binary = config::check_pem("TLS_KEY", &der);
+        let missing = config::check_pem("TLS_CERT", &dir.join("missing.pem"));
+        std::fs::remove_file(&pem).unwrap();
+        std::fs::remove_file(&der).unwrap();
+
+        assert!(valid.is_ok());
+        assert_eq!(
+            binary.unwrap_err().to_string(),
+            format!("invalid value for TLS_KEY: {:?}", der.display().to_string())
+        );
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .starts_with("invalid value for TLS_CERT"));
+    }
+
+    #[test]
+    fn bind_addr_defaults() {
+        let addr = config::parse_bind_addr(None, None).unwrap();
+
+        assert_eq!(addr, ([127, 0, 0, 1], 3030).into());
+    }
+
+    #[test]
+    fn bind_addr_from_values() {
+        let addr = config::parse_bind_addr(Some("0.0.0.0"), Some("8080")).unwrap();
+
+        assert_eq!(addr, ([0, 0, 0, 0], 8080).into());
+    }
+
+    #[test]
+    fn bind_addr_invalid() {
+        let err = config::parse_bind_addr(Some("localhost"), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid value for BIND_ADDR: "localhost""#
+        );
+
+        let err = config::parse_bind_addr(None, Some("99999")).unwrap_err();
+        assert_eq!(err.to_string(), r#"invalid value for PORT: "99999""#);
+    }
+
+    #[tokio::test]
+    async fn payload_too_large() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(vec![b' '; 17 * 1024])
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "too_large");
+    }
+
+    #[tokio::test]
+    async fn configured_body_limit() {
+        let config = ApiConfig {
+            max_body_bytes: Some(64),
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(InMemoryRepo::new(init_state()), config);
+        // trailing whitespace pads the body to the given length
+        let body = |length: usize| {
+            let json = r#"{"lastName": "Doe", "gender": "male"}"#;
+            format!("{}{}", json, " ".repeat(length - json.len()))
+        };
+
+        let at_limit = request()
+            .method("POST")
+            .path("/users")
+            .body(body(64))
+            .reply(&api)
+            .await;
+        let over_limit = request()
+            .method("POST")
+            .path("/users")
+            .body(body(65))
+            .reply(&api)
+            .await;
+
+        assert_eq!(at_limit.status(), StatusCode::CREATED);
+        assert_eq!(over_limit.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn parse_max_body_bytes() {
+        assert_eq!(config::parse_max_body_bytes(None).unwrap(), None);
+        assert_eq!(
+            config::parse_max_body_bytes(Some("65536")).unwrap(),
+            Some(65536)
+        );
+        assert!(config::parse_max_body_bytes(Some("0")).is_err());
+        assert!(config::parse_max_body_bytes(Some("64k")).is_err());
+    }
+
+    #[test]
+    fn parse_max_concurrent_requests() {
+        assert_eq!(config::parse_max_concurrent_requests(None).unwrap(), None);
+        assert_eq!(
+            config::parse_max_concurrent_requests(Some("512")).unwrap(),
+            Some(512)
+        );
+        assert!(config::parse_max_concurrent_requests(Some("0")).is_err());
+        assert!(config::parse_max_concurrent_requests(Some("many")).is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_json() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body("{not json")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "bad_json");
+    }
+
+    #[tokio::test]
+    async fn unknown_route() {
+        let api = api(init_state());
+
+        let response = request().method("GET").path("/nope").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "not_found");
+        assert_eq!(body["error"]["message"], "route not found");
+    }
+
+    #[tokio::test]
+    async fn error_messages_by_language() {
+        let api = api(init_state());
+        let message = |path: &'static str, language: &'static str| {
+            let api = api.clone();
+            async move {
+                let response = request()
+                    .method("GET")
+                    .path(path)
+                    .header("accept-language", language)
+                    .reply(&api)
+                    .await;
+                let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+                body["error"]["message"].as_str().unwrap().to_owned()
+            }
+        };
+
+        assert_eq!(message("/users/42", "en-US").await, "user not found");
+        assert_eq!(
+            message("/users/42", "de-DE").await,
+            "Benutzer nicht gefunden"
+        );
+        assert_eq!(message("/nope", "de").await, "Route nicht gefunden");
+        assert_eq!(message("/users/42", "fr").await, "user not found");
+        let invalid_query = message("/users?sort=email", "de").await;
+        assert!(
+            invalid_query.starts_with("ungültige Query: unknown variant `email`"),
+            "{}",
+            invalid_query
+        );
+    }
+
+    #[test]
+    fn negotiate_language() {
+        assert_eq!(handlers::Language::negotiate(None), handlers::Language::En);
+        assert_eq!(
+            handlers::Language::negotiate(Some("fr, de;q=0.9, en;q=0.8")),
+            handlers::Language::De
+        );
+        assert_eq!(
+            handlers::Language::negotiate(Some("de;q=0.5, en-GB;q=0.8")),
+            handlers::Language::En
+        );
+        assert_eq!(
+            handlers::Language::negotiate(Some("de;q=0, fr")),
+            handlers::Language::En
+        );
+    }
+
+    async fn create_user_error(body: &'static str) -> (StatusCode, serde_json::Value) {
+        let api = api(init_state());
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(body)
+            .reply(&api)
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn missing_field() {
+        let (status, body) = create_user_error(r#"{"gender": "male"}"#).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains("missing field `lastName`"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn wrong_field_type() {
+        let (status, body) = create_user_error(r#"{"lastName": 1, "gender": "male"}"#).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(
+            message.contains("invalid type: integer `1`, expected a string"),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn create_user_empty_last_name() {
+        let (status, body) = create_user_error(r#"{"lastName": "", "gender": "male"}"#).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["code"], "validation");
+        assert_eq!(body["error"]["details"][0]["field"], "lastName");
+    }
+
+    #[tokio::test]
+    async fn create_user_blank_last_name() {
+        let (status, _) = create_user_error(r#"{"lastName": " \t ", "gender": "male"}"#).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn patch_user_blank_last_name() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state.clone());
+
+        let response = request()
+            .method("PATCH")
+            .path("/users/42")
+            .header("if-match", "1")
+            .body(r#"{"lastName": "  "}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(state.read().await[&42].last_name, "Doe");
+    }
+
+    #[tokio::test]
+    async fn create_user_with_email() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe", "gender": "female", "email": "jane@example.com"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let user: User = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(user.email.as_deref(), Some("jane@example.com"));
+    }
+
+    #[tokio::test]
+    async fn create_user_invalid_email() {
+        for email in [
+            "jane",
+            "jane@",
+            "@example.com",
+            "jane@@example.com",
+            "ja ne@example.com",
+        ] {
+            let body = format!(
+                r#"{{"lastName": "Doe", "gender": "female", "email": "{}"}}"#,
+                email
+            );
+            let api = api(init_state());
+
+            let response = request()
+                .method("POST")
+                .path("/users")
+                .body(body)
+                .reply(&api)
+                .await;
+
+            assert_eq!(
+                response.status(),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{}",
+                email
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn create_user_without_email() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body.get("email").is_none());
+    }
+
+    #[tokio::test]
+    async fn active_requests_gauge() {
+        let state = init_state();
+        let api = api(state.clone());
+
+        // the listing waits for the lock, which keeps the request in flight
+        let guard = state.write().await;
+        let pending = tokio::spawn(async move { list_ids(&api, "/users").await });
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while sample("http_server_active_requests", &[]) < 1. {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("held request should be counted as active");
+
+        drop(guard);
+        assert!(pending.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn response_size_histogram() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state);
+        let users = [("http_target", "/users")];
+        let before = sample("http_server_response_size", &users);
+
+        let response = request().method("GET").path("/users").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(sample("http_server_response_size", &users) >= before + 1.);
+    }
+
+    #[tokio::test]
+    async fn request_size_histogram() {
+        let api = api(init_state());
+        let users = [("http_target", "/users")];
+
+        for (size, status) in [
+            (1024, StatusCode::BAD_REQUEST),
+            (17 * 1024, StatusCode::PAYLOAD_TOO_LARGE),
+        ] {
+            let before = sample("http_server_request_size", &users);
+
+            let response = request()
+                .method("POST")
+                .path("/users")
+                .body(vec![b' '; size])
+                .reply(&api)
+                .await;
+
+            assert_eq!(response.status(), status);
+            assert!(sample("http_server_request_size", &users) >= before + 1.);
+        }
+    }
+
+    #[tokio::test]
+    async fn sheds_requests_over_concurrency_limit() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let api = filters::concurrency_limit(Some(semaphore.clone()))
+            .map(|_permit| "ok")
+            .recover(handlers::handle_rejection);
+        let held = semaphore.clone().acquire_many_owned(2).await.unwrap();
+
+        let shed = request().reply(&api).await;
+        drop(held);
+        let served = request().reply(&api).await;
+
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers()["retry-after"], "1");
+        let body: serde_json::Value = serde_json::from_slice(shed.body()).unwrap();
+        assert_eq!(body["error"]["code"], "overloaded");
+        assert_eq!(served.status(), StatusCode::OK);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn panicking_handler() {
+        async fn handler() -> Result<String, warp::Rejection> {
+            panic!("intentional panic")
+        }
+        let api = warp::any()
+            .and_then(|| filters::catch_panics(handler()))
+            .recover(handlers::handle_rejection);
+        let before = sample("panics_total", &[]);
+
+        let response = request().reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "internal");
+        assert!(sample("panics_total", &[]) >= before + 1.);
+    }
+
+    #[tokio::test]
+    async fn errors_by_reason() {
+        let api = api(init_state());
+        let too_large = format!(r#"{{"lastName": "{}"}}"#, "x".repeat(20 * 1024));
+        let cases = [
+            ("not_found", "GET", "/users/42", String::new()),
+            (
+                "validation",
+                "POST",
+                "/users",
+                r#"{"lastName": " ", "gender": "male"}"#.into(),
+            ),
+            ("bad_json", "POST", "/users", "{".into()),
+            ("too_large", "POST", "/users", too_large),
+            ("invalid_query", "GET", "/users?limit=many", String::new()),
+        ];
+
+        for (reason, method, path, body) in cases {
+            let labels = [("reason", reason)];
+            let before = sample("errors_total", &labels);
+
+            request()
+                .method(method)
+                .path(path)
+                .body(body)
+                .reply(&api)
+                .await;
+
+            assert!(sample("errors_total", &labels) >= before + 1., "{}", reason);
+        }
+    }
+
+    #[test]
+    fn route_templates() {
+        assert_eq!(route_template("/users"), "/users");
+        assert_eq!(route_template("/users/"), "/users");
+        assert_eq!(route_template("/users/42"), "/users/{id}");
+        assert_eq!(route_template("/users/batch"), "/users/batch");
+        assert_eq!(route_template("/users/count"), "/users/count");
+        assert_eq!(route_template("/users/events"), "/users/events");
+        assert_eq!(route_template("/users/search"), "/users/search");
+        assert_eq!(route_template("/users/jane"), "invalid");
+        assert_eq!(route_template("/users/42/friends"), "invalid");
+        assert_eq!(route_template("/"), "invalid");
+        assert_eq!(route_template("/users/42/"), "/users/{id}");
+        assert_eq!(route_template("/users//"), "/users");
+        assert_eq!(route_template("/health/"), "/health");
+        // the routes are case-sensitive, so these don't reach the users routes either
+        assert_eq!(route_template("/Users"), "invalid");
+        assert_eq!(route_template("/users/BATCH"), "invalid");
+    }
+
+    #[test]
+    fn method_labels() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::TRACE), "TRACE");
+        // methods are case-sensitive, a lowercase one is an extension method
+        let lowercase = Method::from_bytes(b"get").unwrap();
+        assert_eq!(method_label(&lowercase), "_OTHER");
+        let custom = Method::from_bytes(b"PURGE").unwrap();
+        assert_eq!(method_label(&custom), "_OTHER");
+    }
+
+    #[tokio::test]
+    async fn duration_series_per_route_and_method() {
+        let api = api(init_state());
+        let series = |method: &'static str, target: &'static str| {
+            sample(
+                "http_server_duration",
+                &[("http_method", method), ("http_target", target)],
+            )
+        };
+        let (get, post, search) = (
+            series("GET", "/users"),
+            series("POST", "/users"),
+            series("POST", "/users/search"),
+        );
+
+        let listed = request().method("GET").path("/users").reply(&api).await;
+        let created = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+        let searched = request()
+            .method("POST")
+            .path("/users/search")
+            .body("{}")
+            .reply(&api)
+            .await;
+
+        assert_eq!(listed.status(), StatusCode::OK);
+        assert_eq!(created.status(), StatusCode::CREATED);
+        assert_eq!(searched.status(), StatusCode::OK);
+        assert!(series("GET", "/users") >= get + 1.);
+        assert!(series("POST", "/users") >= post + 1.);
+        assert!(series("POST", "/users/search") >= search + 1.);
+    }
+
+    #[tokio::test]
+    async fn delete_user_metrics() {
+        let state = init_state();
+        state.write().await.insert(1, user(1));
+        let api = api(state);
+        let labels = [("http_method", "DELETE"), ("http_target", "/users/{id}")];
+        let before = sample("http_server_duration", &labels);
+
+        let response = request()
+            .method("DELETE")
+            .path("/users/1")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(sample("http_server_duration", &labels) >= before + 1.);
+    }
+
+    #[tokio::test]
+    async fn exact_status_code_metrics() {
+        let api = api(init_state());
+        let labels = [
+            ("http_method", "POST"),
+            ("http_status_code", "200"),
+            ("http_status_code_exact", "201"),
+        ];
+        let before = sample("status_codes", &labels);
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(sample("status_codes", &labels) >= before + 1.);
+    }
+
+    #[test]
+    fn parse_histogram_boundaries() {
+        assert_eq!(
+            config::parse_histogram_boundaries(Some("0.01,0.1, 1,5")),
+            [0.01, 0.1, 1., 5.]
+        );
+        assert_eq!(config::parse_histogram_boundaries(Some("250")), [250.]);
+    }
+
+    #[test]
+    fn parse_sampler_ratio() {
+        assert_eq!(config::parse_sampler_ratio(None).unwrap(), 1.);
+        assert_eq!(config::parse_sampler_ratio(Some("0.25")).unwrap(), 0.25);
+        assert_eq!(config::parse_sampler_ratio(Some("0")).unwrap(), 0.);
+        for value in ["", "half", "1.5", "-0.1", "NaN"] {
+            assert!(
+                config::parse_sampler_ratio(Some(value)).is_err(),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn sampler_respects_parent() {
+        let provider = sdk::trace::TracerProvider::builder()
+            .with_config(sdk::trace::config().with_sampler(sampler(0.)))
+            .build();
+        let tracer = provider.get_tracer("test", None);
+        let sampled_parent =
+            opentelemetry::Context::new().with_remote_span_context(SpanContext::new(
+                TraceId::from_u128(1),
+                SpanId::from_u64(1),
+                TRACE_FLAG_SAMPLED,
+                true,
+                TraceState::default(),
+            ));
+
+        let root = tracer.start_with_context("root", opentelemetry::Context::new());
+        let child = tracer.start_with_context("child", sampled_parent);
+
+        assert!(!root.span_context().is_sampled());
+        assert!(child.span_context().is_sampled());
+    }
+
+    #[test]
+    fn parse_histogram_boundaries_fallback() {
+        let defaults = config::parse_histogram_boundaries(None);
+        assert!(!defaults.is_empty());
+        for value in ["", "fast,slow", "1,,2", "5,1", "1,inf", "0.1;0.2"] {
+            assert_eq!(
+                config::parse_histogram_boundaries(Some(value)),
+                defaults,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn users_current_metric() {
+        let state = init_state();
+        state.write().await.extend((1..=3).map(|id| (id, user(id))));
+        state.write().await.insert(
+            4,
+            User {
+                deleted_at: Some(Utc::now()),
+                ..user(4)
+            },
+        );
+        // the metrics exporter has to be set up before the observer is registered, which stays
+        // in the global meter for the rest of the run, but only reads its own repository
+        metrics_exporter();
+        observe_users_current(InMemoryRepo::new(state.clone()));
+
+        assert_eq!(sample("users_current", &[]), 3.);
+        state.write().await.insert(5, user(5));
+        assert_eq!(sample("users_current", &[]), 4.);
+    }
+
+    #[tokio::test]
+    async fn users_by_gender_metric() {
+        let state = init_state();
+        state.write().await.extend(vec![
+            (1, UserBuilder::new(1, "Doe").gender(Gender::Female).build()),
+            (2, UserBuilder::new(2, "Roe").gender(Gender::Female).build()),
+            (3, UserBuilder::new(3, "Poe").gender(Gender::Male).build()),
+            (
+                4,
+                UserBuilder::new(4, "Moe")
+                    .gender(Gender::Other("agender".into()))
+                    .build(),
+            ),
+            (
+                5,
+                UserBuilder::new(5, "Loe")
+                    .gender(Gender::Other("bigender".into()))
+                    .build(),
+            ),
+        ]);
+        state.write().await.insert(
+            6,
+            User {
+                deleted_at: Some(Utc::now()),
+                ..UserBuilder::new(6, "Zoe").gender(Gender::Male).build()
+            },
+        );
+        // like the users_current observer, this one stays registered but only reads its repository
+        metrics_exporter();
+        observe_users_by_gender(InMemoryRepo::new(state.clone()));
+
+        assert_eq!(sample("users_by_gender", &[("gender", "female")]), 2.);
+        assert_eq!(sample("users_by_gender", &[("gender", "male")]), 1.);
+        assert_eq!(sample("users_by_gender", &[("gender", "unspecified")]), 0.);
+        assert_eq!(sample("users_by_gender", &[("gender", "other")]), 2.);
+        state.write().await.insert(7, user(7));
+        assert_eq!(sample("users_by_gender", &[("gender", "unspecified")]), 1.);
+    }
+
+    #[tokio::test]
+    async fn users_created_metric() {
+        let _exclusive = METRICS_LOCK.write().await;
+        let state = init_state();
+        let api = filters::users(
+            InMemoryRepo::new(state),
+            metrics_exporter(),
+            &ApiConfig::default(),
+        );
+        let create = |body: &'static str| {
+            request()
+                .method("POST")
+                .path("/users")
+                .body(body)
+                .reply(&api)
+        };
+        let created = sample("users_created_total", &[]);
+        let total = sample("users_total", &[]);
+
+        let response = create(r#"{"lastName": " ", "gender": "female"}"#).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(sample("users_created_total", &[]), created);
+        assert_eq!(sample("users_total", &[]), total);
+
+        let response = create(r#"{"lastName": "Doe", "gender": "female"}"#).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(sample("users_created_total", &[]), created + 1.);
+        assert_eq!(sample("users_total", &[]), total + 1.);
+
+        let response = request()
+            .method("DELETE")
+            .path("/users/1")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(sample("users_created_total", &[]), created + 1.);
+        assert_eq!(sample("users_total", &[]), total);
+    }
+
+    #[tokio::test]
+    async fn storage_lock_wait_metric() {
+        let api = api(init_state());
+        let wait_sum = || {
+            METRICS_EXPORTER
+                .registry()
+                .gather()
+                .iter()
+                .filter(|family| family.get_name() == "web_service_storage_lock_wait_ms")
+                .flat_map(|family| family.get_metric())
+                .map(|metric| metric.get_histogram().get_sample_sum())
+                .sum::<f64>()
+        };
+        let reads = sample("storage_lock_wait_ms", &[("mode", "read")]);
+
+        let response = request().method("GET").path("/users").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(sample("storage_lock_wait_ms", &[("mode", "read")]) >= reads + 1.);
+        assert!(wait_sum() >= 0.);
+    }
+
+    #[tokio::test]
+    async fn protected_metrics() {
+        let config = ApiConfig {
+            api_token: Some("api-secret".into()),
+            metrics_token: Some("metrics-secret".into()),
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(InMemoryRepo::new(init_state()), config);
+        let scrape = |authorization: Option<&'static str>| {
+            let mut scrape = request().method("GET").path("/metrics");
+            if let Some(authorization) = authorization {
+                scrape = scrape.header("authorization", authorization);
+            }
+            scrape.reply(&api)
+        };
+
+        let without = scrape(None).await;
+        let api_token = scrape(Some("Bearer api-secret")).await;
+        let metrics_token = scrape(Some("Bearer metrics-secret")).await;
+
+        assert_eq!(without.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(api_token.status(), StatusCode::FORBIDDEN);
+        assert_eq!(metrics_token.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn parse_metrics_token() {
+        assert_eq!(
+            config::parse_metrics_token(false, Some("metrics"), Some("api")).unwrap(),
+            None
+        );
+        assert_eq!(
+            config::parse_metrics_token(true, Some("metrics"), Some("api")).unwrap(),
+            Some("metrics".into())
+        );
+        assert_eq!(
+            config::parse_metrics_token(true, Some(""), Some("api")).unwrap(),
+            Some("api".into())
+        );
+        assert!(config::parse_metrics_token(true, None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_formats() {
+        let api = api(init_state());
+        request().method("GET").path("/users").reply(&api).await;
+
+        let openmetrics = request()
+            .method("GET")
+            .path("/metrics")
+            .header(
+                "accept",
+                "application/openmetrics-text; version=1.0.0, text/plain;q=0.5",
+            )
+            .reply(&api)
+            .await;
+        let prometheus = request().method("GET").path("/metrics").reply(&api).await;
+
+        assert_eq!(openmetrics.status(), StatusCode::OK);
+        assert_eq!(
+            openmetrics.headers()["content-type"],
+            "application/openmetrics-text; version=1.0.0; charset=utf-8"
+        );
+        let body = String::from_utf8_lossy(openmetrics.body());
+        assert!(body.ends_with("# EOF\n"), "{}", body);
+        assert!(
+            body.contains("# TYPE web_service_status_codes counter\n"),
+            "{}",
+            body
+        );
+        assert!(
+            body.contains("\nweb_service_status_codes_total{"),
+            "{}",
+            body
+        );
+
+        assert_eq!(prometheus.status(), StatusCode::OK);
+        assert_eq!(
+            prometheus.headers()["content-type"],
+            "text/plain; version=0.0.4; charset=utf-8"
+        );
+        let body = String::from_utf8_lossy(prometheus.body());
+        assert!(
+            body.contains("# TYPE web_service_status_codes counter\n"),
+            "{}",
+            body
+        );
+        assert!(!body.contains("# EOF"), "{}", body);
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct JsonSeries {
+        name: String,
+        #[serde(rename = "type")]
+        kind: String,
+        labels: HashMap<String, String>,
+        value: f64,
+        count: Option<u64>,
+    }
+
+    #[tokio::test]
+    async fn json_metrics() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        request()
+            .method("GET")
+            .path("/users/42")
+            .reply(&api(state))
+            .await;
+        let exporter = JsonExporter::new(metrics_exporter().registry().clone());
+        let api = filters::users(
+            InMemoryRepo::new(init_state()),
+            exporter,
+            &ApiConfig::default(),
+        );
+
+        let response = request().method("GET").path("/metrics").reply(&api).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let series: Vec<JsonSeries> = serde_json::from_slice(response.body()).unwrap();
+        let status_codes = series
+            .iter()
+            .find(|series| {
+                series.name == "web_service_status_codes"
+                    && series
+                        .labels
+                        .get("http_status_code_exact")
+                        .map(String::as_str)
+                        == Some("200")
+            })
+            .unwrap();
+        assert_eq!(status_codes.kind, "counter");
+        assert!(status_codes.value >= 1.);
+        assert_eq!(status_codes.count, None);
+        let duration = series
+            .iter()
+            .find(|series| series.name == "web_service_http_server_duration")
+            .unwrap();
+        assert_eq!(duration.kind, "histogram");
+        assert!(duration.count.unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    async fn metrics_namespace() {
+        request()
+            .method("GET")
+            .path("/users")
+            .reply(&api(init_state()))
+            .await;
+
+        let names: Vec<String> = metrics_exporter()
+            .registry()
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_owned())
+            .collect();
+        assert!(names.contains(&"web_service_incoming_requests".to_owned()));
+        assert!(
+            names.iter().all(|name| name.starts_with("web_service_")),
+            "{:?}",
+            names
+        );
+    }
+
+    #[test]
+    fn parse_metrics_namespace() {
+        assert_eq!(config::parse_metrics_namespace(None), "web_service");
+        assert_eq!(config::parse_metrics_namespace(Some(" users ")), "users");
+        assert_eq!(config::parse_metrics_namespace(Some("")), "");
+    }
+
+    #[test]
+    fn parse_metrics_format() {
+        use config::{parse_metrics_format, MetricsFormat};
+
+        assert_eq!(
+            parse_metrics_format(None).unwrap(),
+            MetricsFormat::Prometheus
+        );
+        assert_eq!(
+            parse_metrics_format(Some("prometheus")).unwrap(),
+            MetricsFormat::Prometheus
+        );
+        assert_eq!(
+            parse_metrics_format(Some("json")).unwrap(),
+            MetricsFormat::Json
+        );
+        assert!(parse_metrics_format(Some("xml")).is_err());
+    }
+
+    #[test]
+    fn encode_text_skips_broken_families() {
+        let mut valid = MetricFamily::default();
+        valid.set_name("valid".into());
+        valid.set_field_type(MetricType::COUNTER);
+        valid.set_metric(vec![Metric::default()].into());
+        // families without metrics are rejected by the encoder
+        let mut empty = MetricFamily::default();
+        empty.set_name("empty".into());
+
+        let buf = encode_text(&[empty, valid]);
+
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("# TYPE valid counter\nvalid 0\n"), "{}", text);
+        assert!(!text.contains("empty"), "{}", text);
+    }
+
+    fn protected_api() -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        let config = ApiConfig {
+            api_token: Some("s3cret".into()),
+            ..ApiConfig::default()
+        };
+        api_with_config(InMemoryRepo::new(init_state()), config)
+    }
+
+    async fn create_with_authorization(authorization: Option<&str>) -> Response<Bytes> {
+        let mut request = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Doe", "gender": "female"}"#);
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        request.reply(&protected_api()).await
+    }
+
+    #[tokio::test]
+    async fn auth_missing_token() {
+        let response = create_with_authorization(None).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn auth_wrong_token() {
+        for authorization in ["Bearer guess", "Bearer s3cret!", "Basic s3cret"] {
+            let response = create_with_authorization(Some(authorization)).await;
+
+            let expected = if authorization.starts_with("Bearer") {
+                StatusCode::FORBIDDEN
+            } else {
+                StatusCode::UNAUTHORIZED
+            };
+            assert_eq!(response.status(), expected, "{}", authorization);
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_correct_token() {
+        let response = create_with_authorization(Some("Bearer s3cret")).await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn auth_reads_stay_public() {
+        let response = request()
+            .method("GET")
+            .path("/users")
+            .reply(&protected_api())
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn create_user_rate_limit() {
+        let config = ApiConfig {
+            create_rate_limit: Some(2),
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(InMemoryRepo::new(init_state()), config);
+        let create = |ip: [u8; 4]| {
+            request()
+                .method("POST")
+                .path("/users")
+                .remote_addr((ip, 4711).into())
+                .body(r#"{"lastName": "Doe", "gender": "female"}"#)
+                .reply(&api)
+        };
+
+        assert_eq!(create([10, 0, 0, 1]).await.status(), StatusCode::CREATED);
+        assert_eq!(create([10, 0, 0, 1]).await.status(), StatusCode::CREATED);
+        let response = create([10, 0, 0, 1]).await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=30).contains(&retry_after), "{}", retry_after);
+        assert_eq!(create([10, 0, 0, 2]).await.status(), StatusCode::CREATED);
+    }
+
+    #[test]
+    fn parse_rate_limit() {
+        assert_eq!(config::parse_rate_limit(None).unwrap(), None);
+        assert_eq!(config::parse_rate_limit(Some("10")).unwrap(), Some(10));
+        assert!(config::parse_rate_limit(Some("0")).is_err());
+        assert!(config::parse_rate_limit(Some("lots")).is_err());
+    }
+
+    fn cors_api() -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        let config = ApiConfig {
+            cors_origins: Some(CorsOrigins::List(vec!["https://app.example".into()])),
+            ..ApiConfig::default()
+        };
+        api_with_config(InMemoryRepo::new(init_state()), config)
+    }
+
+    #[tokio::test]
+    async fn cors_allowed_origin() {
+        let api = cors_api();
+
+        let preflight = request()
+            .method("OPTIONS")
+            .path("/users")
+            .header("origin", "https://app.example")
+            .header("access-control-request-method", "POST")
+            .header(
+                "access-control-request-headers",
+                "content-type, authorization",
+            )
+            .reply(&api)
+            .await;
+        let response = request()
+            .method("GET")
+            .path("/users")
+            .header("origin", "https://app.example")
+            .reply(&api)
+            .await;
+
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+        let headers = preflight.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://app.example"
+        );
+        let methods = headers["access-control-allow-methods"].to_str().unwrap();
+        assert!(methods.contains("POST"), "{}", methods);
+        let allowed = headers["access-control-allow-headers"].to_str().unwrap();
+        assert!(allowed.contains("authorization"), "{}", allowed);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://app.example"
+        );
+    }
+
+    async fn cors_preflight(method: &str, request_headers: &str) -> Response<Bytes> {
+        request()
+            .method("OPTIONS")
+            .path("/users")
+            .header("origin", "https://app.example")
+            .header("access-control-request-method", method)
+            .header("access-control-request-headers", request_headers)
+            .reply(&cors_api())
+            .await
+    }
+
+    #[tokio::test]
+    async fn cors_allows_idempotency_key() {
+        let preflight = cors_preflight("POST", "content-type, idempotency-key").await;
+
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn cors_allows_if_match() {
+        let preflight = cors_preflight("PATCH", "if-match").await;
+
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+        let allowed = preflight.headers()["access-control-allow-headers"]
+            .to_str()
+            .unwrap();
+        assert!(allowed.contains("if-match"), "{}", allowed);
+    }
+
+    #[tokio::test]
+    async fn cors_allows_tenant_id() {
+        let preflight = cors_preflight("GET", "x-tenant-id").await;
+
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn cors_request_id() {
+        let preflight = cors_preflight("GET", "x-request-id").await;
+        let response = request()
+            .path("/users")
+            .header("origin", "https://app.example")
+            .reply(&cors_api())
+            .await;
+
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+        let exposed = response.headers()["access-control-expose-headers"]
+            .to_str()
+            .unwrap();
+        for header in ["x-request-id", "x-trace-id", "etag", "x-total-count"] {
+            assert!(exposed.contains(header), "{}", exposed);
+        }
+    }
+
+    #[tokio::test]
+    async fn cors_disallowed_origin() {
+        let api = cors_api();
+
+        let preflight = request()
+            .method("OPTIONS")
+            .path("/users")
+            .header("origin", "https://evil.example")
+            .header("access-control-request-method", "POST")
+            .reply(&api)
+            .await;
+        let response = request()
+            .method("GET")
+            .path("/users")
+            .header("origin", "https://evil.example")
+            .reply(&api)
+            .await;
+
+        assert_eq!(preflight.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none());
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "cors_forbidden");
+    }
+
+    #[test]
+    fn parse_cors_origins() {
+        assert_eq!(config::parse_cors_origins(None).unwrap(), None);
+        assert_eq!(
+            config::parse_cors_origins(Some("*")).unwrap(),
+            Some(CorsOrigins::Any)
+        );
+        assert_eq!(
+            config::parse_cors_origins(Some("https://a.example, http://b.example:8080")).unwrap(),
+            Some(CorsOrigins::List(vec![
+                "https://a.example".into(),
+                "http://b.example:8080".into()
+            ]))
+        );
+        for value in ["a.example", "https://a.example/", "https://a.example,"] {
+            assert!(
+                config::parse_cors_origins(Some(value)).is_err(),
+                "{}",
+                value
+            );
+        }
+    }
+
+    /// Takes its time listing users.
+    #[derive(Clone)]
+    struct SlowRepo(InMemoryRepo);
+
+    #[async_trait]
+    impl Repository for SlowRepo {
+        fn count_active(&self) -> Option<ActiveUsers> {
+            self.0.count_active()
+        }
+
+        async fn list(&self) -> Result<Vec<User>, RepositoryError> {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            self.0.list().await
+        }
+
+        async fn get(&self, id: u64) -> Result<User, RepositoryError> {
+            self.0.get(id).await
+        }
+
+        async fn create(&self, new_user: NewUser) -> Result<User, RepositoryError> {
+            self.0.create(new_user).await
+        }
+
+        async fn create_many(&self, new_users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+            self.0.create_many(new_users).await
+        }
+
+        async fn replace_all(&self, users: Vec<User>) -> Result<Vec<User>, RepositoryError> {
+            self.0.replace_all(users).await
+        }
+
+        async fn update(&self, user: User) -> Result<User, RepositoryError> {
+            self.0.update(user).await
+        }
+
+        async fn delete(&self, id: u64) -> Result<(), RepositoryError> {
+            self.0.delete(id).await
+        }
+
+        async fn ping(&self) -> Result<(), RepositoryError> {
+            self.0.ping().await
+        }
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let config = ApiConfig {
+            request_timeout: Some(Duration::from_millis(20)),
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(SlowRepo(InMemoryRepo::new(init_state())), config);
+
+        let slow = request().method("GET").path("/users").reply(&api).await;
+        let fast = request().method("GET").path("/users/42").reply(&api).await;
+
+        assert_eq!(slow.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_slice(slow.body()).unwrap();
+        assert_eq!(body["error"]["code"], "timeout");
+        assert_eq!(fast.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn parse_request_timeout() {
+        assert_eq!(config::parse_request_timeout(None).unwrap(), None);
+        assert_eq!(
+            config::parse_request_timeout(Some("1500")).unwrap(),
+            Some(Duration::from_millis(1500))
+        );
+        assert!(config::parse_request_timeout(Some("0")).is_err());
+        assert!(config::parse_request_timeout(Some("1s")).is_err());
+    }
+
+    async fn gunzip(body: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        GzipDecoder::new(body)
+            .read_to_end(&mut decoded)
+            .await
+            .unwrap();
+        decoded
+    }
+
+    #[tokio::test]
+    async fn gzip_compression() {
+        let api = api(init_state());
+
+        let plain = request().method("GET").path("/users").reply(&api).await;
+        let gzipped = request()
+            .method("GET")
+            .path("/users")
+            .header("accept-encoding", "br;q=1.0, gzip;q=0.8")
+            .reply(&api)
+            .await;
+
+        assert_eq!(plain.status(), StatusCode::OK);
+        assert!(plain.headers().get("content-encoding").is_none());
+        assert_eq!(gzipped.status(), StatusCode::OK);
+        assert_eq!(gzipped.headers()["content-encoding"], "gzip");
+        assert_eq!(gzipped.headers()["vary"], "accept-encoding");
+        assert_ne!(gzipped.body(), plain.body());
+        assert_eq!(gunzip(gzipped.body()).await, plain.body().to_vec());
+    }
+
+    #[tokio::test]
+    async fn metrics_are_only_compressed_on_request() {
+        let api = api(init_state());
+
+        let scrape = request().method("GET").path("/metrics").reply(&api).await;
+        let gzipped = request()
+            .method("GET")
+            .path("/metrics")
+            .header("accept-encoding", "gzip")
+            .reply(&api)
+            .await;
+
+        assert!(scrape.headers().get("content-encoding").is_none());
+        assert!(String::from_utf8_lossy(scrape.body()).contains("# TYPE"));
+        assert_eq!(gzipped.headers()["content-encoding"], "gzip");
+        let body = gunzip(gzipped.body()).await;
+        assert!(String::from_utf8_lossy(&body).contains("# TYPE"));
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        use filters::Encoding;
+
+        assert_eq!(filters::negotiate_encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            filters::negotiate_encoding("deflate, gzip"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            filters::negotiate_encoding("gzip;q=0, deflate"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(filters::negotiate_encoding("br, identity"), None);
+        assert_eq!(filters::negotiate_encoding(""), None);
+    }
+
+    #[tokio::test]
+    async fn list_users_etag() {
+        let api = api(init_state());
+
+        let listed = request().method("GET").path("/users").reply(&api).await;
+        let etag = listed.headers()["etag"].to_str().unwrap().to_owned();
+        let unchanged = request()
+            .method("GET")
             .path("/users")
-            .body(
-                r#"{
-                    "invalid": 123
-                }"#,
-            )
+            .header("if-none-match", &etag)
+            .reply(&api)
+            .await;
+        let stale = request()
+            .method("GET")
+            .path("/users")
+            .header("if-none-match", "W/\"0000000000000000\"")
+            .reply(&api)
+            .await;
+
+        assert_eq!(listed.status(), StatusCode::OK);
+        assert!(etag.starts_with("W/\""));
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(unchanged.headers()["etag"], etag.as_str());
+        assert!(unchanged.body().is_empty());
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert_eq!(stale.body(), listed.body());
+    }
+
+    #[tokio::test]
+    async fn list_users_etag_changes_with_users() {
+        let api = api(init_state());
+
+        let before = request().method("GET").path("/users").reply(&api).await;
+        request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Roe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+        let after = request()
+            .method("GET")
+            .path("/users")
+            .header("if-none-match", before.headers()["etag"].clone())
+            .reply(&api)
+            .await;
+
+        assert_eq!(after.status(), StatusCode::OK);
+        assert_ne!(after.headers()["etag"], before.headers()["etag"]);
+    }
+
+    #[test]
+    fn etag_ignores_order() {
+        let users = vec![user(1), user(2), user(3)];
+        let reversed: Vec<User> = users.iter().rev().cloned().collect();
+
+        assert_eq!(
+            handlers::etag(&users, "application/json"),
+            handlers::etag(&reversed, "application/json")
+        );
+        assert_ne!(
+            handlers::etag(&users, "application/json"),
+            handlers::etag(&users[1..], "application/json")
+        );
+        assert_ne!(
+            handlers::etag(&users, "application/json"),
+            handlers::etag(&users, "application/x-ndjson")
+        );
+    }
+
+    async fn create_idempotently(
+        api: &(impl Filter<Extract = impl Reply, Error = Infallible> + 'static),
+        key: &str,
+        body: &str,
+    ) -> Response<Bytes> {
+        request()
+            .method("POST")
+            .path("/users")
+            .header("idempotency-key", key)
+            .body(body)
+            .reply(api)
+            .await
+    }
+
+    #[tokio::test]
+    async fn create_user_idempotency_replay() {
+        let api = api(init_state());
+        let body = r#"{"lastName": "Roe", "gender": "female"}"#;
+
+        let first = create_idempotently(&api, "a1b2", body).await;
+        let retried = create_idempotently(&api, "a1b2", body).await;
+        let other = create_idempotently(&api, "c3d4", body).await;
+
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(first.headers().get("idempotent-replayed").is_none());
+        assert_eq!(retried.status(), StatusCode::CREATED);
+        assert_eq!(retried.headers()["idempotent-replayed"], "true");
+        assert_eq!(retried.body(), first.body());
+        assert_eq!(other.status(), StatusCode::CREATED);
+        assert_ne!(other.body(), first.body());
+        assert_eq!(list_ids(&api, "/users").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn create_user_idempotency_key_reused() {
+        let api = api(init_state());
+
+        let first =
+            create_idempotently(&api, "a1b2", r#"{"lastName": "Roe", "gender": "female"}"#).await;
+        let reused =
+            create_idempotently(&api, "a1b2", r#"{"lastName": "Doe", "gender": "male"}"#).await;
+
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(reused.body()).unwrap();
+        assert_eq!(body["error"]["code"], "idempotency_key_reused");
+        assert_eq!(list_ids(&api, "/users").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn create_user_idempotency_skips_invalid_requests() {
+        let api = api(init_state());
+
+        let invalid =
+            create_idempotently(&api, "a1b2", r#"{"lastName": "", "gender": "female"}"#).await;
+        let fixed =
+            create_idempotently(&api, "a1b2", r#"{"lastName": "Roe", "gender": "female"}"#).await;
+
+        assert_eq!(invalid.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(fixed.status(), StatusCode::CREATED);
+    }
+
+    fn named(id: u64, first_name: Option<&str>, last_name: &str) -> User {
+        let builder = UserBuilder::new(id, last_name);
+        match first_name {
+            Some(first_name) => builder.first_name(first_name).build(),
+            None => builder.build(),
+        }
+    }
+
+    async fn sorted_api() -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+        let state = init_state();
+        state.write().await.extend(
+            vec![
+                named(1, Some("Jane"), "Roe"),
+                named(2, None, "Doe"),
+                named(3, Some("Adam"), "Smith"),
+                named(4, Some("Zoe"), "Doe"),
+            ]
+            .into_iter()
+            .map(|user| (user.id, user)),
+        );
+        api(state)
+    }
+
+    #[tokio::test]
+    async fn list_users_sorted() {
+        let api = sorted_api().await;
+
+        assert_eq!(list_ids(&api, "/users?sort=id").await, [1, 2, 3, 4]);
+        assert_eq!(
+            list_ids(&api, "/users?sort=id&order=desc").await,
+            [4, 3, 2, 1]
+        );
+        // ties keep their order
+        assert_eq!(list_ids(&api, "/users?sort=lastName").await, [2, 4, 1, 3]);
+        assert_eq!(
+            list_ids(&api, "/users?sort=lastName&order=desc").await,
+            [3, 1, 2, 4]
+        );
+        assert_eq!(list_ids(&api, "/users?sort=firstName").await, [2, 3, 1, 4]);
+        assert_eq!(
+            list_ids(&api, "/users?sort=firstName&order=desc").await,
+            [4, 1, 3, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn list_users_sorted_before_pagination() {
+        let api = sorted_api().await;
+
+        let ids = list_ids(&api, "/users?sort=firstName&order=desc&limit=2&offset=1").await;
+
+        assert_eq!(ids, [1, 3]);
+    }
+
+    #[tokio::test]
+    async fn list_users_invalid_sort() {
+        let api = sorted_api().await;
+
+        let field = request()
+            .method("GET")
+            .path("/users?sort=email")
+            .reply(&api)
+            .await;
+        let order = request()
+            .method("GET")
+            .path("/users?sort=id&order=up")
+            .reply(&api)
+            .await;
+
+        assert_eq!(field.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(order.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(field.body());
+        assert!(body.contains("unknown variant `email`"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn list_users_selected_fields() {
+        let state = init_state();
+        state.write().await.extend((1..=2).map(|id| (id, user(id))));
+        let api = api(state);
+
+        let response = request()
+            .method("GET")
+            .path("/users?fields=id,lastName")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let users: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            users,
+            serde_json::json!([{"id": 1, "lastName": "Doe"}, {"id": 2, "lastName": "Doe"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn list_users_unknown_field() {
+        let api = api(init_state());
+
+        let response = request()
+            .method("GET")
+            .path("/users?fields=id,name")
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(body.contains("unknown field `name`"), "{}", body);
+    }
+
+    #[test]
+    fn name_contains_query() {
+        let jane = named(1, Some("Jane"), "Doe");
+        let anonymous = named(2, None, "Smith");
+
+        assert!(name_contains(&jane, "jan"));
+        assert!(name_contains(&jane, "doe"));
+        assert!(name_contains(&jane, "DOE"));
+        assert!(!name_contains(&jane, "smith"));
+        assert!(name_contains(&anonymous, "mit"));
+        assert!(!name_contains(&anonymous, "jane"));
+        assert!(name_contains(&anonymous, ""));
+    }
+
+    #[tokio::test]
+    async fn list_users_search() {
+        let api = sorted_api().await;
+
+        assert_eq!(list_ids(&api, "/users?q=doe").await, [2, 4]);
+        assert_eq!(list_ids(&api, "/users?q=JA").await, [1]);
+        assert_eq!(list_ids(&api, "/users?q=").await, [1, 2, 3, 4]);
+        assert_eq!(
+            list_ids(&api, "/users?q=o&sort=lastName&order=desc&limit=2").await,
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn search_by_gender_and_name() {
+        let female = |user: User| User {
+            gender: Gender::Female,
+            ..user
+        };
+        let users = vec![
+            female(named(1, Some("Jane"), "Doe")),
+            named(2, Some("John"), "Doe"),
+            female(named(3, Some("Mary"), "Roe")),
+            female(named(4, None, "Doerr")),
+        ];
+        let search: SearchRequest = serde_json::from_value(serde_json::json!({
+            "gender": "female",
+            "q": "doe",
+            "sort": "id",
+            "order": "desc",
+            "limit": 1,
+        }))
+        .unwrap();
+
+        let result = search.run(users);
+
+        assert_eq!(result.total, 2);
+        assert_eq!(
+            result.users.iter().map(|user| user.id).collect::<Vec<_>>(),
+            [4]
+        );
+    }
+
+    #[tokio::test]
+    async fn search_users() {
+        let api = sorted_api().await;
+
+        let response = request()
+            .method("POST")
+            .path("/users/search")
+            .body(r#"{"q": "doe", "minId": 3, "sort": "lastName"}"#)
+            .reply(&api)
+            .await;
+        let inverted = request()
+            .method("POST")
+            .path("/users/search")
+            .body(r#"{"minId": 3, "maxId": 2}"#)
             .reply(&api)
             .await;
 
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-count"], "1");
+        let users: Vec<User> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(users.iter().map(|user| user.id).collect::<Vec<_>>(), [4]);
+        assert_eq!(inverted.status(), StatusCode::BAD_REQUEST);
+    }
+
+    fn with_log_filter(directive: &str) -> (LogBuffer, LogFilter, DefaultGuard) {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let (env_filter, handle) = reload::Layer::new(EnvFilter::new(directive));
+        let subscriber = tracing_subscriber::registry()
+            .with(env_filter)
+            .with(bunyan::JsonStorageLayer)
+            .with(bunyan::BunyanFormattingLayer::new(
+                "test".into(),
+                move || writer.clone(),
+            ));
+        let guard = tracing::subscriber::set_default(subscriber);
+        (logs, LogFilter::new(handle), guard)
+    }
+
+    async fn put_log_level(
+        log_filter: Option<LogFilter>,
+        api_token: Option<&str>,
+        body: &str,
+    ) -> Response<Bytes> {
+        let config = ApiConfig {
+            log_filter,
+            api_token: api_token.map(Into::into),
+            ..ApiConfig::default()
+        };
+        let api = api_with_config(InMemoryRepo::new(init_state()), config);
+        request()
+            .method("PUT")
+            .path("/log-level")
+            .body(body)
+            .reply(&api)
+            .await
+    }
+
+    #[tokio::test]
+    async fn log_level_reload() {
+        let (logs, log_filter, _guard) = with_log_filter("warn");
+
+        tracing::info!("before reload");
+        let response = put_log_level(Some(log_filter), None, r#"{"directive": "debug"}"#).await;
+        tracing::info!("after reload");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), r#"{"directive":"debug"}"#);
+        let messages: Vec<String> = logs
+            .records()
+            .iter()
+            .map(|record| record["msg"].as_str().unwrap().to_owned())
+            .collect();
+        assert!(!messages.iter().any(|msg| msg.ends_with("before reload")));
+        assert!(messages.iter().any(|msg| msg.ends_with("after reload")));
+    }
+
+    #[tokio::test]
+    async fn log_level_invalid_directive() {
+        let (_logs, log_filter, _guard) = with_log_filter("warn");
+
+        let response =
+            put_log_level(Some(log_filter), None, r#"{"directive": "foo=shouting"}"#).await;
+
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "invalid_directive");
+    }
+
+    #[tokio::test]
+    async fn log_level_requires_token() {
+        let (_logs, log_filter, _guard) = with_log_filter("warn");
+
+        let response = put_log_level(
+            Some(log_filter),
+            Some("s3cret"),
+            r#"{"directive": "debug"}"#,
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn log_level_disabled() {
+        let response = put_log_level(None, None, r#"{"directive": "debug"}"#).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn list_users_records_count() {
+        let (logs, _guard) = with_bunyan_logs();
+        let state = init_state();
+        state.write().await.extend((1..=5).map(|id| (id, user(id))));
+        let api = api(state);
+
+        list_ids(&api, "/users?offset=1&limit=3").await;
+
+        let records = logs.records();
+        let end = records
+            .iter()
+            .find(|record| record["msg"] == "[LIST_USERS - END]")
+            .unwrap();
+        assert_eq!(end["users.count"], 3);
+    }
+
+    #[tokio::test]
+    async fn access_log() {
+        let (logs, _guard) = with_bunyan_logs();
+        let api = api(init_state());
+
+        request().method("GET").path("/users/42").reply(&api).await;
+        request().method("GET").path("/metrics").reply(&api).await;
+
+        let records = logs.records();
+        let access: Vec<_> = records
+            .iter()
+            .filter(|record| record["msg"] == "request completed")
+            .collect();
+        assert_eq!(access.len(), 1);
+        assert_eq!(access[0]["level"], 30);
+        assert_eq!(access[0]["http.method"], "GET");
+        assert_eq!(access[0]["http.target"], "/users/42");
+        assert_eq!(access[0]["http.status_code"], 404);
+        assert!(access[0]["duration_ms"].is_u64());
+        assert!(access[0]["remote_addr"].is_string());
+    }
+
+    #[tokio::test]
+    async fn validation_failures_are_span_events() {
+        let (logs, _guard) = with_bunyan_logs();
+        let api = api(init_state());
+
+        let response = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "   ", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let records = logs.records();
+        let event = records
+            .iter()
+            .find(|record| record["msg"] == "[CREATE_USER - EVENT] validation failed")
+            .unwrap();
+        assert_eq!(event["level"], 40);
+        assert_eq!(event["field"], "lastName");
+        assert_eq!(event["reason"], "must not be blank");
+    }
+
+    #[tokio::test]
+    async fn head_users() {
+        let state = init_state();
+        state.write().await.extend((1..=3).map(|id| (id, user(id))));
+        let api = api(state);
+        let labels = [("http_method", "HEAD"), ("http_target", "/users")];
+        let before = sample("http_server_duration", &labels);
+
+        let get = request().method("GET").path("/users").reply(&api).await;
+        let head = request().method("HEAD").path("/users").reply(&api).await;
+
+        assert_eq!(head.status(), StatusCode::OK);
+        assert!(head.body().is_empty());
+        assert_eq!(
+            head.headers()["content-type"],
+            get.headers()["content-type"]
+        );
+        assert_eq!(head.headers()["etag"], get.headers()["etag"]);
+        assert_eq!(
+            head.headers()["content-length"],
+            get.body().len().to_string().as_str()
+        );
+        assert!(sample("http_server_duration", &labels) >= before + 1.);
+    }
+
+    #[tokio::test]
+    async fn create_user_location() {
+        let api = api(init_state());
+
+        let created = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Roe", "gender": "female"}"#)
+            .reply(&api)
+            .await;
+        let location = created.headers()["location"].to_str().unwrap();
+        let fetched = request().method("GET").path(location).reply(&api).await;
+
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let user: User = serde_json::from_slice(created.body()).unwrap();
+        assert_eq!(location, format!("/users/{}", user.id));
+        assert_eq!(fetched.status(), StatusCode::OK);
+        assert_eq!(fetched.body(), created.body());
+    }
+
+    #[tokio::test]
+    async fn list_users_total_count() {
+        let api = api(init_state());
+        for last_name in ["Doe", "Roe", "Moe", "Poe", "Zoe"] {
+            let response = request()
+                .method("POST")
+                .path("/users")
+                .body(format!(
+                    r#"{{"lastName": "{}", "gender": "male"}}"#,
+                    last_name
+                ))
+                .reply(&api)
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        let page = request()
+            .method("GET")
+            .path("/users?limit=2&offset=1")
+            .reply(&api)
+            .await;
+        let filtered = request()
+            .method("GET")
+            .path("/users?q=oe&limit=1")
+            .reply(&api)
+            .await;
+        let searched = request()
+            .method("GET")
+            .path("/users?q=zoe")
+            .reply(&api)
+            .await;
+
+        let users: Vec<User> = serde_json::from_slice(page.body()).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(page.headers()["x-total-count"], "5");
+        assert_eq!(filtered.headers()["x-total-count"], "5");
+        assert_eq!(searched.headers()["x-total-count"], "1");
+    }
+
+    #[test]
+    fn gender_round_trip() {
+        for (gender, json) in [
+            (Gender::Female, r#""female""#),
+            (Gender::Unspecified, r#""unspecified""#),
+            (Gender::Other("non-binary".into()), r#""non-binary""#),
+        ] {
+            assert_eq!(serde_json::to_string(&gender).unwrap(), json);
+            assert_eq!(serde_json::from_str::<Gender>(json).unwrap(), gender);
+        }
+    }
+
+    #[test]
+    fn gender_other_limits() {
+        assert!(serde_json::from_str::<Gender>(r#""  ""#).is_err());
+        assert!(serde_json::from_str::<Gender>(&format!(r#""{}""#, "x".repeat(65))).is_err());
+        assert_eq!(
+            serde_json::from_str::<Gender>(r#""Female""#).unwrap(),
+            Gender::Other("Female".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn create_user_other_gender() {
+        let api = api(init_state());
+
+        let created = request()
+            .method("POST")
+            .path("/users")
+            .body(r#"{"lastName": "Roe", "gender": "agender"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let user: User = serde_json::from_slice(created.body()).unwrap();
+        assert_eq!(user.gender, Gender::Other("agender".into()));
+        assert_eq!(list_ids(&api, "/users?gender=agender").await, [user.id]);
+        assert!(list_ids(&api, "/users?gender=female").await.is_empty());
+    }
+
+    async fn patch_with_if_match(
+        api: &(impl Filter<Extract = impl Reply, Error = Infallible> + 'static),
+        if_match: Option<&str>,
+    ) -> Response<Bytes> {
+        let mut request = request()
+            .method("PATCH")
+            .path("/users/42")
+            .body(r#"{"lastName": "Roe"}"#);
+        if let Some(if_match) = if_match {
+            request = request.header("if-match", if_match);
+        }
+        request.reply(api).await
+    }
+
+    #[tokio::test]
+    async fn update_with_current_version() {
+        let state = init_state();
+        state.write().await.insert(42, user(42));
+        let api = api(state.clone());
+
+        let patched = patch_with_if_match(&api, Some("1")).await;
+        let replaced = request()
+            .method("PUT")
+            .path("/users/42")
+            .header("if-match", r#""2""#)
+            .body(r#"{"id": 42, "lastName": "Poe", "gender": "male"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(patched.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(patched.body()).unwrap();
+        assert_eq!(body["version"], 2);
+        assert_eq!(replaced.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(replaced.body()).unwrap();
+        assert_eq!(body["version"], 3);
+        assert_eq!(state.read().await[&42].last_name, "Poe");
+    }
+
+    #[tokio::test]
+    async fn update_with_stale_version() {
+        let state = init_state();
+        state.write().await.insert(
+            42,
+            User {
+                version: 2,
+                ..user(42)
+            },
+        );
+        let api = api(state.clone());
+
+        let stale = patch_with_if_match(&api, Some("1")).await;
+        let garbled = patch_with_if_match(&api, Some("latest")).await;
+        let missing = patch_with_if_match(&api, None).await;
+
+        assert_eq!(stale.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_slice(stale.body()).unwrap();
+        assert_eq!(body["error"]["code"], "version_conflict");
+        assert_eq!(garbled.status(), StatusCode::CONFLICT);
+        assert_eq!(missing.status(), StatusCode::PRECONDITION_REQUIRED);
+        let users = state.read().await;
+        assert_eq!(users[&42].last_name, "Doe");
+        assert_eq!(users[&42].version, 2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn update_with_stale_version_sqlite() {
+        let repo = SqliteRepo::connect("sqlite::memory:").await.unwrap();
+        let user = repo
+            .create(NewUser {
+                first_name: None,
+                last_name: "Doe".into(),
+                gender: Gender::Male,
+                email: None,
+            })
+            .await
+            .unwrap();
+        let api = api_with(repo);
+        let path = format!("/users/{}", user.id);
+
+        let current = request()
+            .method("PATCH")
+            .path(&path)
+            .header("if-match", "1")
+            .body(r#"{"lastName": "Roe"}"#)
+            .reply(&api)
+            .await;
+        let stale = request()
+            .method("PATCH")
+            .path(&path)
+            .header("if-match", "1")
+            .body(r#"{"lastName": "Poe"}"#)
+            .reply(&api)
+            .await;
+
+        assert_eq!(current.status(), StatusCode::OK);
+        assert_eq!(stale.status(), StatusCode::CONFLICT);
+    }
+
+    async fn soft_delete(api: &(impl Filter<Extract = impl Reply, Error = Infallible> + 'static)) {
+        let deleted = request().method("DELETE").path("/users/2").reply(api).await;
+        let deleted_again = request().method("DELETE").path("/users/2").reply(api).await;
+        let fetched = request().method("GET").path("/users/2").reply(api).await;
+
+        assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+        assert_eq!(deleted_again.status(), StatusCode::NOT_FOUND);
+        assert_eq!(fetched.status(), StatusCode::NOT_FOUND);
+        assert_eq!(list_ids(api, "/users").await, [1, 3]);
+        assert_eq!(list_ids(api, "/users?includeDeleted=true").await, [1, 2, 3]);
+        let response = request()
+            .method("GET")
+            .path("/users?includeDeleted=true&q=doe2")
+            .reply(api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body[0]["deletedAt"].is_string());
+    }
+
+    #[tokio::test]
+    async fn soft_delete_user() {
+        let state = init_state();
+        state.write().await.extend((1..=3).map(|id| {
+            let user = User {
+                last_name: format!("Doe{}", id),
+                ..user(id)
+            };
+            (id, user)
+        }));
+
+        soft_delete(&api(state)).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn soft_delete_user_sqlite() {
+        let repo = SqliteRepo::connect("sqlite::memory:").await.unwrap();
+        for id in 1..=3 {
+            repo.create(NewUser {
+                first_name: None,
+                last_name: format!("Doe{}", id),
+                gender: Gender::Male,
+                email: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        soft_delete(&api_with(repo)).await;
     }
 }